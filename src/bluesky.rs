@@ -0,0 +1,327 @@
+use std::env;
+
+use dotenvy::dotenv;
+use serde::Deserialize;
+use url::Url;
+
+use crate::{
+    feed_source::{Entry, FeedSource, ParsedFeed, SourceKind},
+    fetch_rss::{Tweet, TweetEntities, TwiInfo},
+    utils,
+};
+
+const DEFAULT_APPVIEW_URL: &str = "https://public.api.bsky.app/";
+// 1 回の取得で読む投稿の数
+const FEED_LIMIT: &str = "30";
+
+#[derive(Debug, Deserialize)]
+struct AuthorFeed {
+    feed: Vec<FeedViewPost>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FeedViewPost {
+    post: PostView,
+    // リポストであれば、リポストしたアカウント
+    reason: Option<Reason>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Reason {
+    #[serde(rename = "$type")]
+    kind: String,
+    by: Option<Profile>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Profile {
+    handle: String,
+    display_name: Option<String>,
+    avatar: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PostView {
+    uri: String,
+    author: Profile,
+    record: PostRecord,
+    embed: Option<Embed>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PostRecord {
+    #[serde(default)]
+    text: String,
+    created_at: String,
+    reply: Option<serde_json::Value>,
+}
+
+// 画像 (images#view)、引用 (record#view)、その両方 (recordWithMedia#view)
+#[derive(Debug, Deserialize)]
+struct Embed {
+    #[serde(default)]
+    images: Vec<Image>,
+    record: Option<EmbedRecord>,
+    media: Option<Box<Embed>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Image {
+    fullsize: String,
+}
+
+// recordWithMedia#view では、引用した投稿が record の中にもう 1 段入っている
+#[derive(Debug, Deserialize)]
+struct EmbedRecord {
+    uri: Option<String>,
+    author: Option<Profile>,
+    record: Option<Box<EmbedRecord>>,
+}
+
+// AppView (公開 API) の URL
+pub fn appview_from_env() -> anyhow::Result<Url> {
+    dotenv().ok();
+    let appview = env::var("BSKY_APPVIEW_URL").unwrap_or_else(|_| DEFAULT_APPVIEW_URL.to_string());
+    Ok(Url::parse(&appview)?)
+}
+
+// expected input: bsky:alice.bsky.social, https://bsky.app/profile/alice.bsky.social
+// expected output: https://bsky.app/profile/alice.bsky.social
+pub fn rss_url(input: &str) -> Option<Url> {
+    let handle = if let Some(handle) = input.strip_prefix("bsky:") {
+        handle.trim_start_matches('@')
+    } else {
+        input
+            .strip_prefix("https://bsky.app/profile/")?
+            .trim_end_matches('/')
+    };
+    let handle = handle.to_lowercase();
+    // ハンドルはドメイン名の形式
+    let is_handle = handle.contains('.')
+        && handle
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+    if !is_handle {
+        return None;
+    }
+
+    Url::parse(&format!("https://bsky.app/profile/{handle}")).ok()
+}
+
+pub fn author_feed_url(appview: &Url, handle: &str) -> anyhow::Result<Url> {
+    let mut feed_url = appview.join("/xrpc/app.bsky.feed.getAuthorFeed")?;
+    feed_url
+        .query_pairs_mut()
+        .append_pair("actor", handle)
+        .append_pair("limit", FEED_LIMIT);
+    Ok(feed_url)
+}
+
+// Bluesky のアカウント (https://bsky.app/profile/{handle} をキーに、AppView から取得する)
+pub struct BlueskySource;
+
+impl FeedSource for BlueskySource {
+    fn fetch_url(&self, rss_url: &Url, _host: &str, _remote_id: &str) -> anyhow::Result<Url> {
+        author_feed_url(&appview_from_env()?, &self.account(rss_url)?)
+    }
+
+    // expected output: alice.bsky.social
+    fn account(&self, rss_url: &Url) -> anyhow::Result<String> {
+        let handle = rss_url
+            .path()
+            .strip_prefix("/profile/")
+            .filter(|handle| !handle.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("invalid bluesky url: {rss_url}"))?;
+        Ok(handle.to_lowercase())
+    }
+
+    fn parse(&self, rss_url: &Url, body: &[u8]) -> anyhow::Result<ParsedFeed> {
+        let author_feed = serde_json::from_slice::<AuthorFeed>(body)?;
+        let account = self.account(rss_url)?;
+
+        // リポストの投稿者は別のアカウントのため、購読しているアカウント自身の情報を探す
+        let profile = author_feed.feed.iter().find_map(|item| match &item.reason {
+            Some(reason) => reason.by.as_ref(),
+            None => Some(&item.post.author),
+        });
+        let info = TwiInfo {
+            icon_url: profile
+                .and_then(|p| p.avatar.as_deref())
+                .and_then(|avatar| Url::parse(avatar).ok()),
+            display_name: profile
+                .and_then(|p| p.display_name.as_deref())
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .unwrap_or(&account)
+                .to_string(),
+            profile_url: Some(rss_url.clone()),
+            account,
+            source: SourceKind::Bluesky,
+        };
+        let last_date = author_feed
+            .feed
+            .first()
+            .and_then(|item| utils::rfc2822(&item.post.record.created_at));
+        let entries = author_feed
+            .feed
+            .into_iter()
+            .filter_map(feed_entry)
+            .collect();
+
+        Ok(ParsedFeed {
+            info,
+            entries,
+            last_date,
+        })
+    }
+}
+
+fn feed_entry(item: FeedViewPost) -> Option<Entry> {
+    let FeedViewPost { post, reason } = item;
+    let is_retweet = reason.is_some_and(|reason| reason.kind.ends_with("#reasonRepost"));
+    // 同じ投稿のリポストと元の投稿を別のものとして扱う
+    let id = if is_retweet {
+        format!("repost:{}", post.uri)
+    } else {
+        post.uri.clone()
+    };
+
+    let url = post_url(&post.uri, &post.author.handle)?;
+    let (pics, quote_url) = post.embed.map_or_else(Default::default, |embed| {
+        let media = embed.media.as_deref().unwrap_or(&embed);
+        let pics = media
+            .images
+            .iter()
+            .filter_map(|image| Url::parse(&image.fullsize).ok())
+            .collect::<Vec<_>>();
+        let quoted = embed
+            .record
+            .as_ref()
+            .map(|record| record.record.as_deref().unwrap_or(record));
+        let quote_url = quoted
+            .and_then(|record| post_url(record.uri.as_deref()?, &record.author.as_ref()?.handle));
+        (pics, quote_url)
+    });
+
+    let tweet = Tweet {
+        twi_url: url.clone(),
+        nitter_url: url,
+        title: None,
+        author: post.author.handle.to_lowercase(),
+        author_name: post
+            .author
+            .display_name
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty()),
+        author_icon: post
+            .author
+            .avatar
+            .and_then(|avatar| Url::parse(&avatar).ok()),
+        text: post.record.text,
+        content_warning: None,
+        pub_date: utils::rfc2822(&post.record.created_at),
+        pics,
+        videos: Vec::new(),
+        is_reply: post.record.reply.is_some(),
+        is_retweet,
+        quote_url,
+        entities: TweetEntities::default(),
+    };
+    Some(Entry { id, tweet })
+}
+
+// expected input: at://did:plc:xxxx/app.bsky.feed.post/{rkey}
+// expected output: https://bsky.app/profile/{handle}/post/{rkey}
+fn post_url(at_uri: &str, handle: &str) -> Option<Url> {
+    let rkey = at_uri
+        .strip_prefix("at://")?
+        .split('/')
+        .nth(2)
+        .filter(|rkey| !rkey.is_empty())?;
+    Url::parse(&format!("https://bsky.app/profile/{handle}/post/{rkey}")).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{fetch_rss, query::FeedCache};
+
+    // 記録した getAuthorFeed のレスポンスを 1 度だけ返す、AppView の代わりのサーバー
+    async fn serve_once(body: &'static str) -> (Url, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let appview = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let len = stream.read(&mut request).await.unwrap();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&request[..len]).to_string()
+        });
+
+        (appview, server)
+    }
+
+    #[tokio::test]
+    async fn author_feed_test() {
+        let body = include_str!("../fixtures/bluesky/get_author_feed.json");
+        let (appview, server) = serve_once(body).await;
+
+        let rss_url = rss_url("bsky:@Alice.bsky.social").unwrap();
+        assert_eq!(
+            "https://bsky.app/profile/alice.bsky.social",
+            rss_url.as_str()
+        );
+        let feed_url = author_feed_url(&appview, "alice.bsky.social").unwrap();
+        let (body, _) = fetch_rss::fetch_rss(&feed_url, &FeedCache::default())
+            .await
+            .unwrap()
+            .unwrap();
+        let request = server.await.unwrap();
+        assert!(request.starts_with(
+            "GET /xrpc/app.bsky.feed.getAuthorFeed?actor=alice.bsky.social&limit=30 "
+        ));
+
+        let feed = BlueskySource.parse(&rss_url, &body).unwrap();
+        assert_eq!("Alice", feed.info.display_name);
+        assert_eq!("alice.bsky.social", feed.info.account);
+
+        let repost = &feed.entries[0];
+        assert_eq!(
+            "repost:at://did:plc:bob/app.bsky.feed.post/3kbob0000001",
+            repost.id
+        );
+        assert!(repost.tweet.is_retweet);
+        assert_eq!("bob.bsky.social", repost.tweet.author);
+        assert_eq!(Some("Bob"), repost.tweet.author_name.as_deref());
+        assert_eq!(
+            "https://bsky.app/profile/bob.bsky.social/post/3kbob0000001",
+            repost.tweet.twi_url.as_str()
+        );
+
+        let quote = &feed.entries[1].tweet;
+        assert_eq!(
+            Some("https://bsky.app/profile/bob.bsky.social/post/3kbob0000000"),
+            quote.quote_url.as_ref().map(Url::as_str)
+        );
+        assert_eq!(1, quote.pics.len());
+
+        let reply = &feed.entries[2].tweet;
+        assert!(reply.is_reply);
+        assert_eq!(
+            vec!["https://cdn.bsky.app/img/feed_fullsize/plain/did:plc:alice/img0@jpeg"],
+            reply.pics.iter().map(Url::as_str).collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+};
+
+use reqwest::{header::RETRY_AFTER, Response};
+use tokio::time::{Duration, Instant};
+
+// この回数連続で失敗したらインスタンスへのリクエストを止める
+const OPEN_THRESHOLD: u32 = 3;
+const BACKOFF_BASE_SECONDS: u64 = 30;
+const BACKOFF_MAX_SECONDS: u64 = 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstanceErrorKind {
+    // 接続できない、応答が途中で切れたなど
+    Transport,
+    // 429 や 5xx
+    Status(u16),
+    // RSS の代わりに HTML (エラーページなど) が返された
+    Html,
+}
+
+// インスタンス自体の不調を表すエラー (429, 5xx, RSS の代わりに HTML が返るなど)
+#[derive(Debug)]
+pub struct InstanceError {
+    pub kind: InstanceErrorKind,
+    pub reason: String,
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for InstanceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "instance error: {}", self.reason)
+    }
+}
+
+impl std::error::Error for InstanceError {}
+
+impl InstanceError {
+    pub fn new(kind: InstanceErrorKind, reason: impl Into<String>) -> Self {
+        Self {
+            kind,
+            reason: reason.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn transport(err: &reqwest::Error) -> Self {
+        Self::new(InstanceErrorKind::Transport, err.to_string())
+    }
+
+    // レスポンスがインスタンスの不調を示していれば、そのエラーを返す
+    pub fn from_response(res: &Response) -> Option<Self> {
+        let status = res.status();
+        if status.as_u16() == 429 || status.is_server_error() {
+            return Some(Self {
+                kind: InstanceErrorKind::Status(status.as_u16()),
+                reason: status.to_string(),
+                retry_after: retry_after(res),
+            });
+        }
+
+        let is_html = res
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("text/html"));
+        if status.is_success() && is_html {
+            return Some(Self::new(
+                InstanceErrorKind::Html,
+                "HTML response instead of RSS",
+            ));
+        }
+
+        None
+    }
+}
+
+// expected input: 秒数、または HTTP-date
+fn retry_after(res: &Response) -> Option<Duration> {
+    let value = res.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let seconds = (date.timestamp() - chrono::Utc::now().timestamp()).max(0);
+    Some(Duration::from_secs(u64::try_from(seconds).ok()?))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::Open => "open",
+            Self::HalfOpen => "half-open",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Transition {
+    pub from: BreakerState,
+    pub to: BreakerState,
+    pub reason: String,
+}
+
+// インスタンスごとのサーキットブレーカー
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: BreakerState,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            state: BreakerState::Closed,
+            failures: 0,
+            retry_at: None,
+        }
+    }
+}
+
+impl CircuitBreaker {
+    // 次にリクエストしてよい時刻
+    pub fn retry_at(&self) -> Option<Instant> {
+        self.retry_at
+    }
+
+    // open の待機時間が過ぎていれば half-open に移り、1 件だけ試す
+    pub fn before_request(&mut self, now: Instant) -> Option<Transition> {
+        if self.state == BreakerState::Open && self.retry_at.is_none_or(|at| at <= now) {
+            return Some(self.transition(BreakerState::HalfOpen, "probing".to_string()));
+        }
+        None
+    }
+
+    pub fn on_success(&mut self) -> Option<Transition> {
+        self.failures = 0;
+        self.retry_at = None;
+
+        (self.state != BreakerState::Closed)
+            .then(|| self.transition(BreakerState::Closed, "recovered".to_string()))
+    }
+
+    pub fn on_failure(&mut self, err: &InstanceError, now: Instant) -> Option<Transition> {
+        self.failures += 1;
+
+        let backoff = jittered(backoff(self.failures));
+        let wait = err
+            .retry_after
+            .map_or(backoff, |retry_after| retry_after.max(backoff));
+        self.retry_at = Some(now + wait);
+
+        // レート制限された場合は、続けて送らないよう 1 回目から止める
+        let should_open = self.state == BreakerState::HalfOpen
+            || self.failures >= OPEN_THRESHOLD
+            || err.kind == InstanceErrorKind::Status(429)
+            || err.retry_after.is_some();
+        (should_open && self.state != BreakerState::Open).then(|| {
+            let reason = format!("{} (retry in {}s)", err.reason, wait.as_secs());
+            self.transition(BreakerState::Open, reason)
+        })
+    }
+
+    fn transition(&mut self, to: BreakerState, reason: String) -> Transition {
+        let from = self.state;
+        self.state = to;
+        Transition { from, to, reason }
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    let exp = failures.saturating_sub(1).min(16);
+    let seconds = BACKOFF_BASE_SECONDS
+        .saturating_mul(2_u64.pow(exp))
+        .min(BACKOFF_MAX_SECONDS);
+    Duration::from_secs(seconds)
+}
+
+// 複数のループが同時に再開しないよう、待機時間を 80% ~ 120% の範囲でずらす
+fn jittered(duration: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish() % 401;
+    let per_mille = 800 + u32::try_from(random).unwrap_or_default();
+    duration * per_mille / 1000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn circuit_breaker_test() {
+        let mut breaker = CircuitBreaker::default();
+        let now = Instant::now();
+        let err = InstanceError::new(InstanceErrorKind::Status(502), "502 Bad Gateway");
+        let html = InstanceError::new(InstanceErrorKind::Html, "HTML response instead of RSS");
+
+        assert!(breaker.on_failure(&err, now).is_none());
+        assert!(breaker.on_failure(&html, now).is_none());
+        let opened = breaker.on_failure(&err, now).unwrap();
+        assert_eq!(BreakerState::Open, opened.to);
+        let retry_at = breaker.retry_at().unwrap();
+        assert!(retry_at >= now + backoff(3) * 8 / 10);
+
+        assert!(breaker.before_request(now).is_none());
+        let probing = breaker.before_request(retry_at).unwrap();
+        assert_eq!(BreakerState::HalfOpen, probing.to);
+
+        // half-open での失敗は再び open に戻る
+        let reopened = breaker.on_failure(&err, retry_at).unwrap();
+        assert_eq!(BreakerState::Open, reopened.to);
+
+        breaker.before_request(breaker.retry_at().unwrap());
+        let closed = breaker.on_success().unwrap();
+        assert_eq!(BreakerState::Closed, closed.to);
+        assert!(breaker.retry_at().is_none());
+
+        // Retry-After が指定されていれば、1 回目でも従う
+        let rate_limited = InstanceError {
+            kind: InstanceErrorKind::Status(429),
+            reason: "429 Too Many Requests".to_string(),
+            retry_after: Some(Duration::from_mins(10)),
+        };
+        assert!(breaker.on_failure(&rate_limited, now).is_some());
+        assert!(breaker.retry_at().unwrap() >= now + Duration::from_mins(10));
+
+        // 429 は Retry-After が無くても 1 回目で止める
+        let mut breaker = CircuitBreaker::default();
+        let too_many = InstanceError::new(InstanceErrorKind::Status(429), "429 Too Many Requests");
+        assert!(breaker.on_failure(&too_many, now).is_some());
+    }
+}
@@ -0,0 +1,112 @@
+use std::fmt;
+
+use url::Url;
+
+use crate::{circuit_breaker::InstanceError, utils};
+
+// コマンドの実行者に返す、原因ごとのエラー
+#[derive(Debug)]
+pub enum CommandError {
+    // 正しい使い方を添える
+    InvalidInput(&'static str),
+    InvalidUrl(String),
+    UnknownAccount(String),
+    InvalidFeed(String),
+    InstanceUnreachable(String),
+    AlreadySubscribed(String),
+    NotSubscribed(String),
+    NoMatchingSubscription(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidInput(usage) => write!(f, "入力が正しくありません。使い方: `{usage}`"),
+            Self::InvalidUrl(url) => write!(f, "URL が正しくありません: {url}"),
+            Self::UnknownAccount(account) => write!(
+                f,
+                "{} は存在しないか、取得できないアカウントです。",
+                utils::display_account(account)
+            ),
+            Self::InvalidFeed(url) => {
+                write!(
+                    f,
+                    "{url} は RSS / Atom のフィードとして読み込めませんでした。"
+                )
+            }
+            Self::InstanceUnreachable(nitter) => write!(
+                f,
+                "{nitter} に接続できませんでした。時間をおいて再度お試しください。"
+            ),
+            Self::AlreadySubscribed(account) => write!(
+                f,
+                "{} は既にこのチャンネルで収集中です。",
+                utils::display_account(account)
+            ),
+            Self::NotSubscribed(account) => write!(
+                f,
+                "{} はこのチャンネルで収集していません。",
+                utils::display_account(account)
+            ),
+            Self::NoMatchingSubscription(target) => {
+                write!(f, "{target} に該当する購読はこのチャンネルにありません。")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl CommandError {
+    // CommandError 以外のエラーも、実行者が読める文面にする
+    pub fn reply_text(err: &anyhow::Error) -> String {
+        if let Some(err) = err.downcast_ref::<Self>() {
+            return err.to_string();
+        }
+
+        let unreachable = err.chain().find_map(|cause| {
+            if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+                let nitter = err.url().and_then(Url::host_str).unwrap_or("インスタンス");
+                return Some(nitter.to_string());
+            }
+            cause
+                .is::<InstanceError>()
+                .then(|| "インスタンス".to_string())
+        });
+        if let Some(nitter) = unreachable {
+            return Self::InstanceUnreachable(nitter).to_string();
+        }
+
+        format!("コマンドを実行できませんでした。({err})")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit_breaker::InstanceErrorKind;
+
+    #[test]
+    fn reply_text_test() {
+        let err = anyhow::Error::from(CommandError::NotSubscribed("twitterjp".to_string()));
+        assert_eq!(
+            "@twitterjp はこのチャンネルで収集していません。",
+            CommandError::reply_text(&err)
+        );
+
+        let err = anyhow::Error::from(InstanceError::new(
+            InstanceErrorKind::Status(502),
+            "502 Bad Gateway",
+        ));
+        assert_eq!(
+            "インスタンス に接続できませんでした。時間をおいて再度お試しください。",
+            CommandError::reply_text(&err)
+        );
+
+        let err = anyhow::anyhow!("unknown option: --likes");
+        assert_eq!(
+            "コマンドを実行できませんでした。(unknown option: --likes)",
+            CommandError::reply_text(&err)
+        );
+    }
+}
@@ -1,68 +1,601 @@
-use std::{env, sync::Arc};
+use std::{env, str::SplitWhitespace, sync::Arc, time::Duration};
 
 use anyhow::Context;
 use dotenvy::dotenv;
+use regex::Regex;
 use slack_morphism::{
     prelude::{
-        SlackApiChatPostMessageRequest, SlackClientEventsUserState, SlackCommandEvent,
+        SlackApiPostWebhookMessageRequest, SlackClientEventsUserState, SlackCommandEvent,
         SlackCommandEventResponse, SlackHyperClient,
     },
-    SlackApiTokenType, SlackChannelId, SlackMessageContent,
+    SlackChannelId, SlackMessageContent, SlackMessageResponseType, SlackResponseUrl,
 };
+use sqlx::SqlitePool;
+use tokio::task::JoinHandle;
 use url::Url;
 
-use crate::{fetch_rss, query, utils};
+use crate::{
+    bluesky,
+    circuit_breaker::{InstanceError, InstanceErrorKind},
+    command_error::CommandError,
+    feed_loops::FeedLoops,
+    feed_source::{self, SourceKind},
+    fetch_rss::{self, TwiInfo},
+    filter::{FilterKind, SubscriptionOptions},
+    mastodon,
+    query::{self, FeedCache, NewFeed, Subscription},
+    render::{self, ImageMode, Layout},
+    utils,
+};
+
+const LIST_PAGE_SIZE: i64 = 20;
+// この秒数で終わらないコマンドは、結果を後から送る
+const ACK_SECONDS: u64 = 2;
+
+const ADD_USAGE: &str =
+    "/mock_twitter <account | rss-url | @user@host | bsky:handle> [--no-retweets] [--no-replies] [--no-quotes]";
+const ADD_FEED_USAGE: &str = "/mock_twitter add-feed <rss-url | atom-url>";
+const REMOVE_USAGE: &str =
+    "/mock_twitter remove <account | rss-url> / --instance <host> / all [--yes]";
+const LIST_USAGE: &str = "/mock_twitter list [page]";
+const LAYOUT_USAGE: &str = "/mock_twitter layout link | rich";
+const IMAGES_USAGE: &str = "/mock_twitter images blocks | thread";
+const INTERVAL_USAGE: &str = "/mock_twitter interval <account> <minutes (1〜1440)>";
+const FILTER_USAGE: &str = "/mock_twitter filter <account> include | exclude <regex> / clear";
 
 pub async fn command_event_handler(
     event: SlackCommandEvent,
     client: Arc<SlackHyperClient>,
-    _states: SlackClientEventsUserState,
+    states: SlackClientEventsUserState,
 ) -> Result<SlackCommandEventResponse, Box<dyn std::error::Error + Send + Sync>> {
-    let channel_id_command = event.channel_id.clone();
+    let feed_loops = states
+        .read()
+        .await
+        .get_user_state::<FeedLoops>()
+        .context("feed loops are not registered.")?
+        .clone();
 
-    let text = event.text.clone().context("No text")?;
+    let text = event.text.unwrap_or_default();
+    let channel = event.channel_id;
+    let task_client = Arc::clone(&client);
+    let mut task = tokio::spawn(async move {
+        match run_command(&channel, &text, &task_client, &feed_loops).await {
+            Ok(content) => content,
+            Err(err) => {
+                println!("command error: {err:#?}");
+                SlackMessageContent::new().with_text(CommandError::reply_text(&err))
+            }
+        }
+    });
+
+    // Slack は 3 秒以内の応答を求めるため、フィードの取得などで間に合わなければ
+    // 先に受け付けたことだけを返し、結果は response_url に送る
+    let content = if let Ok(content) =
+        tokio::time::timeout(Duration::from_secs(ACK_SECONDS), &mut task).await
+    {
+        content?
+    } else {
+        respond_later(task, client, event.response_url);
+        SlackMessageContent::new().with_text("処理しています…".to_string())
+    };
+
+    // 返信はコマンドを実行した本人にのみ表示する
+    Ok(SlackCommandEventResponse::new(content)
+        .with_response_type(SlackMessageResponseType::Ephemeral))
+}
+
+fn respond_later(
+    task: JoinHandle<SlackMessageContent>,
+    client: Arc<SlackHyperClient>,
+    response_url: SlackResponseUrl,
+) {
+    tokio::spawn(async move {
+        let content = match task.await {
+            Ok(content) => content,
+            Err(err) => {
+                println!("command task failed: {err:#?}");
+                return;
+            }
+        };
+        // response_url への返信は、既定でコマンドを実行した本人にのみ表示される
+        let req = SlackApiPostWebhookMessageRequest::new(content);
+        if let Err(err) = client.respond_to_event(&response_url, &req).await {
+            println!("failed to respond to command: {err:#?}");
+        }
+    });
+}
+
+async fn run_command(
+    channel: &SlackChannelId,
+    text: &str,
+    client: &Arc<SlackHyperClient>,
+    feed_loops: &FeedLoops,
+) -> anyhow::Result<SlackMessageContent> {
+    let db = feed_loops.db();
     let mut args = text.split_whitespace();
-    let first_arg = args.next().context("No text")?;
+    let first_arg = args.next().ok_or(CommandError::InvalidInput(ADD_USAGE))?;
 
-    let content = match first_arg {
-        "remove" => {
-            let account = args.next().context("Invalid input")?;
-            query::remove_rss(&channel_id_command, account).await?;
+    let text = match first_arg {
+        "remove" => remove_command(db, channel, args, feed_loops).await?,
+        "list" => {
+            let page = args
+                .next()
+                .map_or(Ok(1), str::parse::<i64>)
+                .map_err(|_| CommandError::InvalidInput(LIST_USAGE))?;
 
-            SlackMessageContent::new().with_text(format!("@{account} の収集を停止します。"))
+            subscription_list_text(db, channel, page).await?
         }
-        add => {
-            let nitter_url_or_account = url::Url::parse(add).context("Invalid input.");
+        "layout" => {
+            let layout = args
+                .next()
+                .and_then(|s| s.parse::<Layout>().ok())
+                .ok_or(CommandError::InvalidInput(LAYOUT_USAGE))?;
+            query::update_layout(db, channel, layout.as_str()).await?;
 
-            let nitter_url = if let Ok(url) = nitter_url_or_account {
-                url
-            } else {
-                account_to_default_nitter_rss_url(add)?
-            };
+            format!("表示形式を {} に変更しました。", layout.as_str())
+        }
+        "images" => {
+            let image_mode = args
+                .next()
+                .and_then(|s| s.parse::<ImageMode>().ok())
+                .ok_or(CommandError::InvalidInput(IMAGES_USAGE))?;
+            query::update_images(db, channel, image_mode.as_str()).await?;
+
+            format!("画像の送信方法を {} に変更しました。", image_mode.as_str())
+        }
+        "interval" => {
+            let account = args
+                .next()
+                .map(utils::canonical_account)
+                .ok_or(CommandError::InvalidInput(INTERVAL_USAGE))?;
+            let minutes = args
+                .next()
+                .and_then(|s| s.parse::<i64>().ok())
+                .filter(|m| (1..=query::MAX_INTERVAL_MINUTES).contains(m))
+                .ok_or(CommandError::InvalidInput(INTERVAL_USAGE))?;
+            let rss_url = query::fetch_channel_rss_url(db, channel, &account)
+                .await?
+                .ok_or_else(|| CommandError::NotSubscribed(account.clone()))?;
+            query::update_interval(db, &rss_url, channel, minutes).await?;
 
-            let nitter = utils::nitter_url_to_nitter(&nitter_url)?.to_string();
-            let is_exist_nitter = query::nitter_exist(&nitter).await?;
+            // 他のチャンネルの設定は変えず、最も短い間隔で取得する
+            format!(
+                "このチャンネルでの {} の取得間隔を {minutes} 分に変更しました。",
+                utils::display_account(&account)
+            )
+        }
+        "add-feed" => return add_feed_command(db, channel, args, client, feed_loops).await,
+        "instances" => instances_text(db).await?,
+        "filter" => filter_command(db, channel, args).await?,
+        add => return add_command(db, channel, add, args, client, feed_loops).await,
+    };
 
-            query::insert_last_item(&nitter_url).await?;
-            query::insert_feed_channel(&channel_id_command, &nitter_url).await?;
+    Ok(SlackMessageContent::new().with_text(text))
+}
 
-            if !is_exist_nitter {
-                //println!("not exist {nitter}");
-                tokio::spawn(fetch_rss::feed_loop_nitter(Arc::clone(&client), nitter));
-            };
+// 解除する購読の指定方法
+#[derive(Debug, PartialEq, Eq)]
+enum RemoveTarget {
+    Account(String),
+    RssUrl(Url),
+    Instance(String),
+    All,
+}
 
-            let account = utils::url_to_account(&nitter_url)?;
+impl RemoveTarget {
+    // expected input: @twitterjp, https://nitter.net/twitterjp/rss, --instance nitter.net, all
+    fn parse(args: &[&str]) -> anyhow::Result<Self> {
+        let target = match args {
+            ["all"] => Self::All,
+            ["--instance", host] => {
+                let host = if host.contains("://") {
+                    Url::parse(host)
+                        .ok()
+                        .and_then(|url| url.host_str().map(str::to_string))
+                        .ok_or_else(|| CommandError::InvalidUrl((*host).to_string()))?
+                } else {
+                    host.trim_end_matches('/').to_string()
+                };
+                Self::Instance(host.to_lowercase())
+            }
+            [url] if url.contains("://") => {
+                let mut url =
+                    Url::parse(url).map_err(|_| CommandError::InvalidUrl((*url).to_string()))?;
+                url.set_fragment(None);
+                Self::RssUrl(url)
+            }
+            // ホスト名の無い --instance などをアカウント名と取り違えない
+            [account] if !account.starts_with("--") => {
+                Self::Account(utils::canonical_account(account))
+            }
+            _ => return Err(CommandError::InvalidInput(REMOVE_USAGE).into()),
+        };
+        Ok(target)
+    }
 
-            SlackMessageContent::new().with_text(format!("@{account} の収集を開始します。"))
+    fn is_match(&self, subscription: &Subscription) -> bool {
+        match self {
+            Self::Account(account) => subscription.account == *account,
+            // RSS / Atom フィードはクエリや大文字小文字で別のフィードになりうるため、そのまま比べる
+            Self::RssUrl(url) => Url::parse(&subscription.rss_url).is_ok_and(|rss_url| {
+                match subscription.source.parse::<SourceKind>() {
+                    Ok(SourceKind::Rss) => rss_url == *url,
+                    _ => utils::canonical_rss_url(&rss_url) == utils::canonical_rss_url(url),
+                }
+            }),
+            Self::Instance(host) => subscription.nitter == *host,
+            Self::All => true,
         }
+    }
+}
+
+async fn remove_command(
+    db: &SqlitePool,
+    channel: &SlackChannelId,
+    args: SplitWhitespace<'_>,
+    feed_loops: &FeedLoops,
+) -> anyhow::Result<String> {
+    let (flags, args): (Vec<_>, Vec<_>) = args.partition(|arg| *arg == "--yes");
+    let confirmed = !flags.is_empty();
+    let target = RemoveTarget::parse(&args)?;
+
+    let total = query::count_subscriptions(db, channel).await?;
+    let matched = query::fetch_subscriptions(db, channel, total, 0)
+        .await?
+        .into_iter()
+        .filter(|s| target.is_match(s))
+        .collect::<Vec<_>>();
+    if matched.is_empty() {
+        let err = match target {
+            RemoveTarget::Account(account) => CommandError::NotSubscribed(account),
+            RemoveTarget::RssUrl(url) => CommandError::NoMatchingSubscription(url.to_string()),
+            RemoveTarget::Instance(host) => CommandError::NoMatchingSubscription(host),
+            RemoveTarget::All => {
+                return Ok("このチャンネルで収集中のアカウントはありません。".to_string())
+            }
+        };
+        return Err(err.into());
+    }
+
+    let lines = matched
+        .iter()
+        .map(|s| format!("• {} ({})", utils::display_account(&s.account), s.nitter))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    // アカウント名以外での指定は複数件を解除しうるため、確認してから解除する
+    if !confirmed && !matches!(target, RemoveTarget::Account(_)) {
+        let command = format!("/mock_twitter remove {} --yes", args.join(" "));
+        return Ok(format!(
+            "以下の {} 件の収集を停止します。よろしければ `{command}` を実行してください。\n{lines}",
+            matched.len()
+        ));
+    }
+
+    let rss_urls = matched
+        .iter()
+        .map(|s| s.rss_url.as_str())
+        .collect::<Vec<_>>();
+    let _guard = feed_loops.lock().await;
+    let removed = query::remove_subscriptions(db, channel, &rss_urls).await?;
+
+    let active_nitters = query::fetch_nitters(db).await?;
+    feed_loops.stop_unused(&active_nitters);
+
+    Ok(format!(
+        "以下の {removed} 件の収集を停止しました。\n{lines}"
+    ))
+}
+
+async fn filter_command(
+    db: &SqlitePool,
+    channel: &SlackChannelId,
+    mut args: SplitWhitespace<'_>,
+) -> anyhow::Result<String> {
+    let account = args
+        .next()
+        .map(utils::canonical_account)
+        .ok_or(CommandError::InvalidInput(FILTER_USAGE))?;
+    let rss_url = query::fetch_channel_rss_url(db, channel, &account)
+        .await?
+        .ok_or_else(|| CommandError::NotSubscribed(account.clone()))?;
+
+    let text = match args
+        .next()
+        .ok_or(CommandError::InvalidInput(FILTER_USAGE))?
+    {
+        "clear" => {
+            query::remove_filters(db, &rss_url, channel).await?;
+            format!(
+                "{} の絞り込み条件を削除しました。",
+                utils::display_account(&account)
+            )
+        }
+        kind => {
+            let kind = kind
+                .parse::<FilterKind>()
+                .map_err(|_| CommandError::InvalidInput(FILTER_USAGE))?;
+            // 正規表現は空白を含みうるため、残りの引数をまとめて扱う
+            let pattern = Some(args.collect::<Vec<_>>().join(" "))
+                .filter(|p| !p.is_empty())
+                .ok_or(CommandError::InvalidInput(FILTER_USAGE))?;
+            Regex::new(&pattern).context("Invalid regex")?;
+            query::insert_filter(db, &rss_url, channel, kind, &pattern).await?;
+            format!(
+                "{} に {} `{pattern}` を設定しました。",
+                utils::display_account(&account),
+                kind.as_str()
+            )
+        }
+    };
+
+    Ok(text)
+}
+
+async fn add_command(
+    db: &SqlitePool,
+    channel: &SlackChannelId,
+    add: &str,
+    args: SplitWhitespace<'_>,
+    client: &Arc<SlackHyperClient>,
+    feed_loops: &FeedLoops,
+) -> anyhow::Result<SlackMessageContent> {
+    // 表記揺れで同じフィードを重複して登録しないよう、正規化してから扱う
+    let (nitter_url, kind) = if let Some(rss_url) = bluesky::rss_url(add) {
+        (rss_url, SourceKind::Bluesky)
+    } else if let Some(rss_url) = mastodon::rss_url(add) {
+        (rss_url, SourceKind::Mastodon)
+    } else if add.contains("://") {
+        let url = Url::parse(add).map_err(|_| CommandError::InvalidUrl(add.to_string()))?;
+        (utils::canonical_rss_url(&url), SourceKind::Nitter)
+    } else {
+        let account = utils::canonical_account(add);
+        (
+            account_to_default_nitter_rss_url(&account)?,
+            SourceKind::Nitter,
+        )
+    };
+
+    let account = feed_source::source(kind)
+        .account(&nitter_url)
+        .ok()
+        .filter(|account| !account.is_empty())
+        .ok_or_else(|| CommandError::InvalidUrl(add.to_string()))?;
+    let flags = args.collect::<Vec<_>>();
+
+    // 購読済みのアカウントにオプションのみを指定した場合は設定を変更する
+    if !flags.is_empty() && query::feed_channel_exist(db, channel, &nitter_url).await? {
+        let mut options = query::fetch_options(db, &nitter_url, channel).await?;
+        options.apply_flags(&flags)?;
+        query::update_options(db, &nitter_url, channel, options).await?;
+
+        let text = format!(
+            "{} の設定を変更しました。({options})",
+            utils::display_account(&account)
+        );
+        return Ok(SlackMessageContent::new().with_text(text));
+    }
+
+    let mut options = SubscriptionOptions::default();
+    options.apply_flags(&flags)?;
+
+    if query::feed_channel_exist(db, channel, &nitter_url).await? {
+        return Err(CommandError::AlreadySubscribed(account).into());
+    }
+    // 存在しないアカウントを購読しないよう、登録前に 1 度取得する
+    let (twi_info, remote_id) = if kind == SourceKind::Mastodon {
+        let found = lookup_mastodon_account(&nitter_url, &account).await?;
+        (mastodon::account_info(&found, account.clone()), found.id)
+    } else {
+        let unknown_account = CommandError::UnknownAccount(account.clone());
+        let twi_info = validate_feed(db, &nitter_url, kind, unknown_account).await?;
+        (twi_info, String::new())
+    };
+
+    // 取得に時間のかかる確認を終えてから、ループの起動までを他の変更と直列化する
+    let _guard = feed_loops.lock().await;
+    let feed = NewFeed {
+        account: &account,
+        source: kind,
+        remote_id: &remote_id,
     };
+    let nitter = query::insert_subscription(db, channel, &nitter_url, feed, options)
+        .await?
+        .ok_or_else(|| CommandError::AlreadySubscribed(account.clone()))?;
 
-    send_system_message(client, content, channel_id_command).await?;
+    feed_loops.start(client, &nitter);
 
-    Ok(SlackCommandEventResponse::new(SlackMessageContent::new()))
+    let text = format!(
+        "*{}* ({}) の収集を開始します。",
+        utils::escape_mrkdwn(&twi_info.display_name),
+        utils::display_account(&account)
+    );
+    Ok(SlackMessageContent::new()
+        .with_text(text.clone())
+        .with_blocks(render::subscribed_blocks(&twi_info, &text)))
+}
+
+// RSS / Atom フィードは URL をそのまま購読のキーにする (大文字小文字やクエリが意味を持ちうるため)
+async fn add_feed_command(
+    db: &SqlitePool,
+    channel: &SlackChannelId,
+    mut args: SplitWhitespace<'_>,
+    client: &Arc<SlackHyperClient>,
+    feed_loops: &FeedLoops,
+) -> anyhow::Result<SlackMessageContent> {
+    let (Some(url), None) = (args.next(), args.next()) else {
+        return Err(CommandError::InvalidInput(ADD_FEED_USAGE).into());
+    };
+    let mut rss_url = Url::parse(url).map_err(|_| CommandError::InvalidUrl(url.to_string()))?;
+    if !matches!(rss_url.scheme(), "http" | "https") || rss_url.domain().is_none() {
+        return Err(CommandError::InvalidUrl(url.to_string()).into());
+    }
+    rss_url.set_fragment(None);
+
+    let account = feed_source::source(SourceKind::Rss).account(&rss_url)?;
+    if query::feed_channel_exist(db, channel, &rss_url).await? {
+        return Err(CommandError::AlreadySubscribed(account).into());
+    }
+    let invalid_feed = CommandError::InvalidFeed(rss_url.to_string());
+    let twi_info = validate_feed(db, &rss_url, SourceKind::Rss, invalid_feed).await?;
+
+    let _guard = feed_loops.lock().await;
+    let feed = NewFeed {
+        account: &account,
+        source: SourceKind::Rss,
+        remote_id: "",
+    };
+    let host =
+        query::insert_subscription(db, channel, &rss_url, feed, SubscriptionOptions::default())
+            .await?
+            .ok_or_else(|| CommandError::AlreadySubscribed(account.clone()))?;
+
+    feed_loops.start(client, &host);
+
+    let text = format!(
+        "*{}* ({account}) の収集を開始します。",
+        utils::escape_mrkdwn(&twi_info.display_name)
+    );
+    Ok(SlackMessageContent::new()
+        .with_text(text.clone())
+        .with_blocks(render::subscribed_blocks(&twi_info, &text)))
+}
+
+// フィードを取得して読み込めるかを確かめ、表示名とアイコンを返す
+// 読み込めなければ not_found を返す
+async fn validate_feed(
+    db: &SqlitePool,
+    rss_url: &Url,
+    kind: SourceKind,
+    not_found: CommandError,
+) -> anyhow::Result<TwiInfo> {
+    let source = feed_source::source(kind);
+    // 他のチャンネルで購読中であれば、切り替え後の取得元で確かめる
+    let host = match query::fetch_nitter(db, rss_url).await? {
+        Some(host) => host,
+        None => utils::nitter_url_to_nitter(rss_url)?.to_string(),
+    };
+    // 取得元での ID が必要な Mastodon は、登録時にアカウントを調べて確かめる
+    let fetch_url = source.fetch_url(rss_url, &host, "")?;
+
+    let body = match fetch_rss::fetch_rss(&fetch_url, &FeedCache::default()).await {
+        Ok(Some((body, _))) => body,
+        Ok(None) => return Err(not_found.into()),
+        Err(err) => {
+            // 応答の無いホスト (通信の失敗、429、5xx) と、フィードが見つからない (404 や HTML のページ) 場合を分ける
+            let unreachable = err
+                .downcast_ref::<InstanceError>()
+                .is_some_and(|err| err.kind != InstanceErrorKind::Html)
+                || err
+                    .downcast_ref::<reqwest::Error>()
+                    .is_some_and(|err| err.status().is_none());
+            if unreachable {
+                return Err(CommandError::InstanceUnreachable(host).into());
+            }
+            return Err(not_found.into());
+        }
+    };
+
+    source
+        .parse(rss_url, &body)
+        .map(|feed| feed.info)
+        .map_err(|_| not_found.into())
+}
+
+// Mastodon は投稿の取得にアカウント ID が必要なため、登録時に調べておく
+async fn lookup_mastodon_account(
+    rss_url: &Url,
+    account: &str,
+) -> anyhow::Result<mastodon::Account> {
+    let host = utils::nitter_url_to_nitter(rss_url)?.to_string();
+    let user = account.split('@').next().unwrap_or(account);
+
+    mastodon::lookup_account(rss_url, user)
+        .await
+        .map_err(|err| {
+            // 応答の無いサーバーと、アカウントが見つからない (404 など) 場合を分ける
+            let unreachable = err
+                .downcast_ref::<reqwest::Error>()
+                .is_some_and(|err| err.status().is_none_or(|s| s.is_server_error()));
+            if unreachable {
+                CommandError::InstanceUnreachable(host).into()
+            } else {
+                CommandError::UnknownAccount(account.to_string()).into()
+            }
+        })
+}
+
+async fn subscription_list_text(
+    db: &SqlitePool,
+    channel: &SlackChannelId,
+    page: i64,
+) -> anyhow::Result<String> {
+    let total = query::count_subscriptions(db, channel).await?;
+    if total == 0 {
+        return Ok("このチャンネルで収集中のアカウントはありません。".to_string());
+    }
+
+    let last_page = (total + LIST_PAGE_SIZE - 1) / LIST_PAGE_SIZE;
+    let page = page.clamp(1, last_page);
+    let subscriptions =
+        query::fetch_subscriptions(db, channel, LIST_PAGE_SIZE, (page - 1) * LIST_PAGE_SIZE)
+            .await?;
+
+    let lines = subscriptions
+        .iter()
+        .map(|s| {
+            let last_date = if s.date.is_empty() { "-" } else { &s.date };
+            let added_at = if s.added_at.is_empty() {
+                "-"
+            } else {
+                &s.added_at
+            };
+            format!(
+                "• {} ({})  最終取得: {last_date}  追加: {added_at}",
+                utils::display_account(&s.account),
+                s.nitter
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let header = format!("収集中のアカウント {total} 件 ({page}/{last_page} ページ)");
+    let text = if page < last_page {
+        let next_page = page + 1;
+        format!("{header}\n{lines}\n次のページ: `/mock_twitter list {next_page}`")
+    } else {
+        format!("{header}\n{lines}")
+    };
+
+    Ok(text)
+}
+
+// 各インスタンスのサーキットブレーカーの最新の状態
+async fn instances_text(db: &SqlitePool) -> anyhow::Result<String> {
+    let states = query::fetch_circuit_states(db).await?;
+    if states.is_empty() {
+        return Ok("インスタンスの状態の変化はまだありません。".to_string());
+    }
+
+    let lines = states
+        .iter()
+        .map(|s| {
+            let reason = if s.reason.is_empty() { "-" } else { &s.reason };
+            format!("• {}  {}  ({reason})  {}", s.nitter, s.state, s.changed_at)
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    Ok(format!("インスタンスの状態\n{lines}"))
 }
 
 fn account_to_default_nitter_rss_url(account: &str) -> anyhow::Result<Url> {
+    // Twitter のアカウント名は英数字と _ の 15 文字まで
+    let re = Regex::new(r"^[A-Za-z0-9_]{1,15}$").unwrap();
+    if !re.is_match(account) {
+        return Err(CommandError::UnknownAccount(account.to_string()).into());
+    }
+
     dotenv().ok();
     let default_url_str = env::var("DEFAULT_NITTER_URL")?;
     let default_url = url::Url::parse(&default_url_str)?;
@@ -73,20 +606,141 @@ fn account_to_default_nitter_rss_url(account: &str) -> anyhow::Result<Url> {
     Ok(nitter_rss_url)
 }
 
-async fn send_system_message(
-    client: Arc<SlackHyperClient>,
-    content: SlackMessageContent,
-    channel: SlackChannelId,
-) -> anyhow::Result<()> {
-    let token = utils::get_token(&SlackApiTokenType::Bot)?;
-    let session = client.open_session(&token);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nitter_pool::NitterPool;
+
+    fn subscription(
+        rss_url: &str,
+        account: &str,
+        nitter: &str,
+        source: SourceKind,
+    ) -> Subscription {
+        Subscription {
+            rss_url: rss_url.to_string(),
+            account: account.to_string(),
+            nitter: nitter.to_string(),
+            date: String::new(),
+            added_at: String::new(),
+            source: source.as_str().to_string(),
+        }
+    }
 
-    let req = SlackApiChatPostMessageRequest::new(channel, content);
+    #[test]
+    fn remove_target_test() {
+        let parse = |args: &str| RemoveTarget::parse(&args.split_whitespace().collect::<Vec<_>>());
+        let url = |url: &str| Url::parse(url).unwrap();
 
-    let _message_res = session
-        .chat_post_message(&req)
-        .await
-        .context("failed to post message.")?;
+        assert_eq!(RemoveTarget::All, parse("all").unwrap());
+        assert_eq!(
+            RemoveTarget::Account("twitterjp".to_string()),
+            parse("@TwitterJP").unwrap()
+        );
+        // スキームの有無や末尾の / を問わずホスト名で指定できる
+        for host in ["Nitter.net/", "https://nitter.net/twitterjp/rss"] {
+            assert_eq!(
+                RemoveTarget::Instance("nitter.net".to_string()),
+                parse(&format!("--instance {host}")).unwrap()
+            );
+        }
+        assert_eq!(
+            RemoveTarget::RssUrl(url("https://nitter.net/TwitterJP/rss")),
+            parse("https://nitter.net/TwitterJP/rss#top").unwrap()
+        );
+        assert!(parse("").is_err());
+        assert!(parse("twitterjp nitter.net").is_err());
+        assert!(parse("--instance").is_err());
+
+        let nitter = subscription(
+            "https://nitter.net/twitterjp/rss",
+            "twitterjp",
+            "nitter.net",
+            SourceKind::Nitter,
+        );
+        let feed_a = subscription(
+            "https://www.youtube.com/feeds/videos.xml?channel_id=A",
+            "www.youtube.com/feeds/videos.xml?channel_id=a",
+            "www.youtube.com",
+            SourceKind::Rss,
+        );
+        let feed_b = subscription(
+            "https://www.youtube.com/feeds/videos.xml?channel_id=B",
+            "www.youtube.com/feeds/videos.xml?channel_id=b",
+            "www.youtube.com",
+            SourceKind::Rss,
+        );
+
+        assert!(parse("twitterjp").unwrap().is_match(&nitter));
+        assert!(!parse("twitterjp").unwrap().is_match(&feed_a));
+        // Nitter の URL は正規化して比べる
+        let nitter_url = parse("https://Nitter.net/TwitterJP/rss/?max_position=1").unwrap();
+        assert!(nitter_url.is_match(&nitter));
+        // RSS / Atom フィードはクエリや大文字小文字の違いを別のフィードとして扱う
+        let feed_url = parse("https://www.youtube.com/feeds/videos.xml?channel_id=A").unwrap();
+        assert!(feed_url.is_match(&feed_a));
+        assert!(!feed_url.is_match(&feed_b));
+        let lowercase = parse("https://www.youtube.com/feeds/videos.xml?channel_id=a").unwrap();
+        assert!(!lowercase.is_match(&feed_a));
+
+        let instance = parse("--instance www.youtube.com").unwrap();
+        assert!(instance.is_match(&feed_a) && instance.is_match(&feed_b));
+        assert!(!instance.is_match(&nitter));
+        assert!([&nitter, &feed_a, &feed_b]
+            .iter()
+            .all(|s| RemoveTarget::All.is_match(s)));
+    }
+
+    #[tokio::test]
+    async fn remove_command_test() {
+        let db = query::setup_db("sqlite::memory:").await.unwrap();
+        let feed_loops = FeedLoops::new(NitterPool::new(Vec::new()), db.clone());
+        let channel = SlackChannelId::new("C0000".to_string());
+        for account in ["alice", "bob"] {
+            let rss_url = Url::parse(&format!("https://nitter.net/{account}/rss")).unwrap();
+            let feed = NewFeed {
+                account,
+                source: SourceKind::Nitter,
+                remote_id: "",
+            };
+            query::insert_subscription(
+                &db,
+                &channel,
+                &rss_url,
+                feed,
+                SubscriptionOptions::default(),
+            )
+            .await
+            .unwrap();
+        }
+        let remove = |args: &'static str| {
+            remove_command(&db, &channel, args.split_whitespace(), &feed_loops)
+        };
+
+        // --yes が無ければ対象を示すのみで解除しない
+        let reply = remove("--instance nitter.net").await.unwrap();
+        assert!(reply.contains("/mock_twitter remove --instance nitter.net --yes"));
+        assert_eq!(2, query::count_subscriptions(&db, &channel).await.unwrap());
+
+        let err = remove("--instance example.com --yes").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CommandError>(),
+            Some(CommandError::NoMatchingSubscription(host)) if host == "example.com"
+        ));
+        let err = remove("carol").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CommandError>(),
+            Some(CommandError::NotSubscribed(account)) if account == "carol"
+        ));
+
+        // アカウント名での指定は確認せずに解除する
+        let reply = remove("alice").await.unwrap();
+        assert!(reply.starts_with("以下の 1 件の収集を停止しました。"));
+        let reply = remove("all --yes").await.unwrap();
+        assert!(reply.starts_with("以下の 1 件の収集を停止しました。"));
+        assert_eq!(0, query::count_subscriptions(&db, &channel).await.unwrap());
 
-    Ok(())
+        let reply = remove("all").await.unwrap();
+        assert_eq!("このチャンネルで収集中のアカウントはありません。", reply);
+    }
 }
@@ -0,0 +1,125 @@
+use std::{env, sync::Arc};
+
+use dotenvy::dotenv;
+use slack_morphism::prelude::SlackHyperClient;
+use sqlx::SqlitePool;
+use url::Url;
+
+use crate::{
+    query::{self, FeedHealth},
+    send_message, utils,
+};
+
+const DEFAULT_ALERT_FAILURES: i64 = 10;
+const DEFAULT_ALERT_MINUTES: i64 = 6 * 60;
+
+// 取得に失敗し続けているフィードをチャンネルに知らせる条件
+#[derive(Debug, Clone, Copy)]
+pub struct AlertThreshold {
+    pub failures: i64,
+    pub minutes: i64,
+}
+
+impl Default for AlertThreshold {
+    fn default() -> Self {
+        Self {
+            failures: DEFAULT_ALERT_FAILURES,
+            minutes: DEFAULT_ALERT_MINUTES,
+        }
+    }
+}
+
+impl AlertThreshold {
+    pub fn from_env() -> Self {
+        dotenv().ok();
+        let default = Self::default();
+
+        let parse = |key| {
+            env::var(key)
+                .ok()
+                .and_then(|s| s.parse::<i64>().ok())
+                .filter(|n| *n > 0)
+        };
+
+        Self {
+            failures: parse("FEED_ALERT_FAILURES").unwrap_or(default.failures),
+            minutes: parse("FEED_ALERT_MINUTES").unwrap_or(default.minutes),
+        }
+    }
+
+    // 連続失敗回数、または失敗し続けている時間のどちらかが上限に達したら知らせる (1 度のみ)
+    pub fn should_alert(&self, health: &FeedHealth) -> bool {
+        !health.alerted
+            && (health.failures >= self.failures || health.failing_minutes >= self.minutes)
+    }
+}
+
+pub async fn record_failure(
+    db: &SqlitePool,
+    client: &Arc<SlackHyperClient>,
+    rss_url: &Url,
+    account: &str,
+    err: &anyhow::Error,
+) -> anyhow::Result<()> {
+    let health = query::increment_feed_failures(db, rss_url, &err.to_string()).await?;
+    if !AlertThreshold::from_env().should_alert(&health) {
+        return Ok(());
+    }
+
+    let channels = query::fetch_channels(db, rss_url).await?;
+    let text = format!(
+        "{} の取得に失敗し続けています。(連続 {} 回 / {} 分) 最後のエラー: {err}",
+        utils::display_account(account),
+        health.failures,
+        health.failing_minutes
+    );
+    send_message::send_notice(channels, &text, Arc::clone(client)).await?;
+    query::mark_feed_alerted(db, rss_url).await?;
+
+    Ok(())
+}
+
+pub async fn record_success(
+    db: &SqlitePool,
+    client: &Arc<SlackHyperClient>,
+    rss_url: &Url,
+    account: &str,
+) -> anyhow::Result<()> {
+    let Some(alerted) = query::fetch_feed_alerted(db, rss_url).await? else {
+        return Ok(());
+    };
+
+    // 通知済みのフィードのみ、復旧したことを知らせる
+    if alerted {
+        let channels = query::fetch_channels(db, rss_url).await?;
+        let text = format!("{} の取得が復旧しました。", utils::display_account(account));
+        send_message::send_notice(channels, &text, Arc::clone(client)).await?;
+    }
+
+    // 通知できてから記録を消し、失敗した場合は次の取得で再び知らせる
+    query::delete_feed_health(db, rss_url).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_alert_test() {
+        let threshold = AlertThreshold {
+            failures: 5,
+            minutes: 60,
+        };
+        let health = |failures, failing_minutes, alerted| FeedHealth {
+            failures,
+            failing_minutes,
+            alerted,
+        };
+
+        assert!(!threshold.should_alert(&health(4, 59, false)));
+        assert!(threshold.should_alert(&health(5, 0, false)));
+        assert!(threshold.should_alert(&health(1, 60, false)));
+        // 通知済みであれば、復旧するまで再び知らせない
+        assert!(!threshold.should_alert(&health(100, 600, true)));
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use slack_morphism::prelude::SlackHyperClient;
+use sqlx::SqlitePool;
+use tokio_util::sync::CancellationToken;
+
+use crate::{fetch_rss, nitter_pool::NitterPool};
+
+// インスタンスごとに起動中の取得ループを管理する
+#[derive(Debug, Clone)]
+pub struct FeedLoops {
+    loops: Arc<Mutex<HashMap<String, CancellationToken>>>,
+    pool: NitterPool,
+    db: SqlitePool,
+    // 購読の追加・削除とループの起動・停止を直列化する
+    changes: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl FeedLoops {
+    pub fn new(pool: NitterPool, db: SqlitePool) -> Self {
+        Self {
+            loops: Arc::default(),
+            pool,
+            db,
+            changes: Arc::default(),
+        }
+    }
+
+    pub fn pool(&self) -> &NitterPool {
+        &self.pool
+    }
+
+    pub fn db(&self) -> &SqlitePool {
+        &self.db
+    }
+
+    // 購読の変更からループの起動・停止までの間保持する
+    pub async fn lock(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.changes.lock().await
+    }
+
+    // 既に起動済みのインスタンスであれば何もしない
+    pub fn start(&self, client: &Arc<SlackHyperClient>, nitter: &str) {
+        let mut loops = self.loops.lock().unwrap();
+        if loops.contains_key(nitter) {
+            return;
+        }
+
+        let token = CancellationToken::new();
+        loops.insert(nitter.to_string(), token.clone());
+        tokio::spawn(fetch_rss::feed_loop_nitter(
+            Arc::clone(client),
+            nitter.to_string(),
+            self.clone(),
+            token,
+        ));
+    }
+
+    pub fn stop(&self, nitter: &str) {
+        let token = self.loops.lock().unwrap().remove(nitter);
+        if let Some(token) = token {
+            token.cancel();
+        }
+    }
+
+    // 購読が残っていないインスタンスのループを停止する
+    pub fn stop_unused(&self, active_nitters: &HashSet<String>) {
+        let unused = self
+            .loops
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|nitter| !active_nitters.contains(*nitter))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for nitter in unused {
+            self.stop(&nitter);
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::str::FromStr;
+
+use anyhow::Context;
+use atom_syndication::Feed;
+use rss::Channel;
+use url::{Position, Url};
+
+use crate::{
+    bluesky::BlueskySource,
+    fetch_rss::{self, NitterSource, Tweet, TweetEntities, TwiInfo},
+    mastodon::MastodonSource,
+};
+
+// 購読しているフィードの取得元
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SourceKind {
+    #[default]
+    Nitter,
+    // ブログやリリース情報など、一般的な RSS / Atom フィード
+    Rss,
+    Mastodon,
+    Bluesky,
+}
+
+impl SourceKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Nitter => "nitter",
+            Self::Rss => "rss",
+            Self::Mastodon => "mastodon",
+            Self::Bluesky => "bluesky",
+        }
+    }
+
+    // リツイートに相当する操作の表記
+    pub fn repost_verb(self) -> &'static str {
+        match self {
+            Self::Nitter => "retweeted",
+            Self::Rss => "shared",
+            Self::Mastodon => "boosted",
+            Self::Bluesky => "reposted",
+        }
+    }
+}
+
+impl FromStr for SourceKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nitter" => Ok(Self::Nitter),
+            "rss" => Ok(Self::Rss),
+            "mastodon" => Ok(Self::Mastodon),
+            "bluesky" => Ok(Self::Bluesky),
+            _ => Err(anyhow::anyhow!("unknown source: {s}")),
+        }
+    }
+}
+
+// フィードの 1 件と、配信済みかを判定するための ID
+#[derive(Debug)]
+pub struct Entry {
+    pub id: String,
+    pub tweet: Tweet,
+}
+
+#[derive(Debug)]
+pub struct ParsedFeed {
+    pub info: TwiInfo,
+    // フィードに載っている順 (新しい順)
+    pub entries: Vec<Entry>,
+    pub last_date: Option<String>,
+}
+
+pub trait FeedSource: Sync {
+    // 購読のキーである URL から、実際に取得する URL を求める
+    // remote_id は取得元での購読先の ID (Mastodon のアカウント ID など、不要なら空)
+    fn fetch_url(&self, rss_url: &Url, host: &str, remote_id: &str) -> anyhow::Result<Url>;
+    // last_item.account に記録し、コマンドで購読を指定するための名前
+    fn account(&self, rss_url: &Url) -> anyhow::Result<String>;
+    fn parse(&self, rss_url: &Url, body: &[u8]) -> anyhow::Result<ParsedFeed>;
+    // 取得に失敗し続けた場合に、別のインスタンスへ切り替えられるか
+    fn can_failover(&self) -> bool {
+        false
+    }
+}
+
+pub fn source(kind: SourceKind) -> &'static dyn FeedSource {
+    match kind {
+        SourceKind::Nitter => &NitterSource,
+        SourceKind::Rss => &RssSource,
+        SourceKind::Mastodon => &MastodonSource,
+        SourceKind::Bluesky => &BlueskySource,
+    }
+}
+
+pub struct RssSource;
+
+impl FeedSource for RssSource {
+    fn fetch_url(&self, rss_url: &Url, _host: &str, _remote_id: &str) -> anyhow::Result<Url> {
+        Ok(rss_url.clone())
+    }
+
+    // expected output: blog.rust-lang.org/feed.xml
+    fn account(&self, rss_url: &Url) -> anyhow::Result<String> {
+        Ok(rss_url[Position::BeforeHost..].to_lowercase())
+    }
+
+    fn parse(&self, rss_url: &Url, body: &[u8]) -> anyhow::Result<ParsedFeed> {
+        let account = self.account(rss_url)?;
+        if let Ok(channel) = Channel::read_from(body) {
+            return Ok(parse_rss(&channel, account));
+        }
+        let feed = Feed::read_from(body).context("neither RSS nor Atom")?;
+        Ok(parse_atom(&feed, account))
+    }
+}
+
+fn parse_rss(channel: &Channel, account: String) -> ParsedFeed {
+    let entries = channel
+        .items()
+        .iter()
+        .filter_map(|item| {
+            let link = Url::parse(item.link()?).ok()?;
+            let id = item
+                .guid()
+                .map_or_else(|| link.to_string(), |guid| guid.value().to_string());
+            let summary = item
+                .description()
+                .map(fetch_rss::fetch_tweet_text)
+                .unwrap_or_default();
+            // 画像の enclosure があれば添える
+            let pics = item
+                .enclosure()
+                .filter(|e| e.mime_type().starts_with("image/"))
+                .and_then(|e| Url::parse(e.url()).ok())
+                .into_iter()
+                .collect();
+            let tweet = feed_item(
+                link,
+                item.title(),
+                summary,
+                item.pub_date().map(str::to_string),
+                pics,
+            );
+            Some(Entry { id, tweet })
+        })
+        .collect::<Vec<_>>();
+
+    let icon_url = channel
+        .image()
+        .and_then(|image| Url::parse(image.url()).ok());
+    let last_date = channel
+        .items()
+        .first()
+        .and_then(|item| item.pub_date())
+        .map(str::to_string);
+
+    ParsedFeed {
+        info: TwiInfo {
+            icon_url,
+            display_name: channel.title().to_string(),
+            account,
+            source: SourceKind::Rss,
+            profile_url: Url::parse(channel.link()).ok(),
+        },
+        entries,
+        last_date,
+    }
+}
+
+fn parse_atom(feed: &Feed, account: String) -> ParsedFeed {
+    let entries = feed
+        .entries()
+        .iter()
+        .filter_map(|entry| {
+            let links = entry.links();
+            let href = links
+                .iter()
+                .find(|l| l.rel() == "alternate")
+                .or_else(|| links.first())?
+                .href();
+            let link = Url::parse(href).ok()?;
+            let summary = entry
+                .summary()
+                .map(|s| s.as_str().to_string())
+                .or_else(|| entry.content().and_then(|c| c.value()).map(str::to_string))
+                .map(|s| fetch_rss::fetch_tweet_text(&s))
+                .unwrap_or_default();
+            let pub_date = entry.published().unwrap_or(entry.updated()).to_rfc2822();
+            let tweet = feed_item(
+                link,
+                Some(entry.title().as_str()),
+                summary,
+                Some(pub_date),
+                Vec::new(),
+            );
+            Some(Entry {
+                id: entry.id().to_string(),
+                tweet,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let icon_url = feed
+        .icon()
+        .or_else(|| feed.logo())
+        .and_then(|icon| Url::parse(icon).ok());
+    let last_date = entries.first().and_then(|e| e.tweet.pub_date.clone());
+
+    ParsedFeed {
+        info: TwiInfo {
+            icon_url,
+            display_name: feed.title().as_str().to_string(),
+            account,
+            source: SourceKind::Rss,
+            profile_url: feed.links().first().and_then(|l| Url::parse(l.href()).ok()),
+        },
+        entries,
+        last_date,
+    }
+}
+
+// タイトルの無い項目はリンクをタイトルとして扱う
+fn feed_item(
+    link: Url,
+    title: Option<&str>,
+    summary: String,
+    pub_date: Option<String>,
+    pics: Vec<Url>,
+) -> Tweet {
+    let title = title
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map_or_else(|| link.to_string(), str::to_string);
+    Tweet {
+        twi_url: link.clone(),
+        nitter_url: link,
+        title: Some(title),
+        author: String::new(),
+        author_name: None,
+        author_icon: None,
+        text: summary,
+        content_warning: None,
+        pub_date,
+        pics,
+        videos: Vec::new(),
+        is_reply: false,
+        is_retweet: false,
+        quote_url: None,
+        entities: TweetEntities::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_atom_test() {
+        let body = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Rust Blog</title>
+  <id>https://blog.rust-lang.org/</id>
+  <updated>2023-06-01T00:00:00+00:00</updated>
+  <icon>https://blog.rust-lang.org/images/favicon.png</icon>
+  <entry>
+    <title>Announcing Rust 1.70.0</title>
+    <id>https://blog.rust-lang.org/2023/06/01/Rust-1.70.0.html</id>
+    <link rel="alternate" href="https://blog.rust-lang.org/2023/06/01/Rust-1.70.0.html"/>
+    <updated>2023-06-01T00:00:00+00:00</updated>
+    <summary type="html">&lt;p&gt;The Rust team is happy to announce a new version.&lt;/p&gt;</summary>
+  </entry>
+</feed>"#;
+        let rss_url = Url::parse("https://blog.rust-lang.org/feed.xml").unwrap();
+
+        let feed = RssSource.parse(&rss_url, body.as_bytes()).unwrap();
+
+        assert_eq!("Rust Blog", feed.info.display_name);
+        assert_eq!("blog.rust-lang.org/feed.xml", feed.info.account);
+        let entry = &feed.entries[0];
+        assert_eq!(
+            "https://blog.rust-lang.org/2023/06/01/Rust-1.70.0.html",
+            entry.id
+        );
+        assert_eq!(Some("Announcing Rust 1.70.0"), entry.tweet.title.as_deref());
+        assert_eq!(
+            "The Rust team is happy to announce a new version.",
+            entry.tweet.text
+        );
+        // 日の 0 埋めは chrono のバージョンによって異なるため、時刻として比べる
+        let last_date = feed
+            .last_date
+            .as_deref()
+            .and_then(|date| chrono::DateTime::parse_from_rfc2822(date).ok())
+            .map(|date| date.timestamp());
+        assert_eq!(Some(1_685_577_600), last_date);
+    }
+}
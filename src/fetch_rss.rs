@@ -1,179 +1,538 @@
-use std::sync::Arc;
+use std::{
+    collections::HashSet,
+    sync::{Arc, OnceLock},
+};
 
 use anyhow::Context;
-use futures::StreamExt;
+use reqwest::{
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    StatusCode,
+};
 use rss::{Channel, Item};
-use scraper::{Html, Selector};
+use scraper::Html;
 use slack_morphism::{prelude::SlackHyperClient, SlackChannelId};
+use sqlx::SqlitePool;
 
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
 use url::Url;
 
 use crate::{
-    query::{self, fetch_nitters, fetch_rss_urls},
-    send_message, utils,
+    circuit_breaker::{CircuitBreaker, InstanceError, Transition},
+    feed_health,
+    feed_loops::FeedLoops,
+    feed_source::{self, Entry, FeedSource, ParsedFeed, SourceKind},
+    filter::TweetFilter,
+    query::{self, fetch_feeds, fetch_nitters, FeedCache},
+    scheduler::{FeedSchedule, InstanceLimits},
+    send_message,
+    tweet_content::{self, TweetContent},
+    utils,
 };
 
-const SLEEP_EACH_FETCH_MINUTES: u64 = 5;
+// 次に取得するフィードが無い間も、この間隔で購読の変更を確認する
+const RESYNC_SECONDS: u64 = 60;
+// 接続と、応答を読み終えるまでの制限時間
+const CONNECT_TIMEOUT_SECONDS: u64 = 5;
+const REQUEST_TIMEOUT_SECONDS: u64 = 20;
 
-pub async fn feed_loop(client: Arc<SlackHyperClient>) -> anyhow::Result<()> {
-    let nitters = fetch_nitters().await?;
+pub async fn feed_loop(client: Arc<SlackHyperClient>, feed_loops: FeedLoops) -> anyhow::Result<()> {
+    let nitters = fetch_nitters(feed_loops.db()).await?;
     for nitter in nitters {
-        tokio::spawn(feed_loop_nitter(Arc::clone(&client), nitter));
+        feed_loops.start(&client, &nitter);
+    }
+
+    Ok(())
+}
+pub async fn feed_loop_nitter(
+    client: Arc<SlackHyperClient>,
+    nitter: String,
+    feed_loops: FeedLoops,
+    token: CancellationToken,
+) -> anyhow::Result<()> {
+    let mut schedule = FeedSchedule::new(InstanceLimits::from_env());
+    let mut breaker = CircuitBreaker::default();
+
+    while !token.is_cancelled() {
+        if let Ok(feeds) = fetch_feeds(feed_loops.db(), &nitter).await {
+            schedule.sync(&feeds, Instant::now());
+        }
+
+        // 購読の追加・削除を反映できるよう、待機は一定時間ごとに区切る
+        let resync_at = Instant::now() + Duration::from_secs(RESYNC_SECONDS);
+        // ブレーカーが開いている間、またはバックオフ中は取得を見送る
+        let Some((url, at)) = schedule
+            .next(Instant::now())
+            .map(|(url, at)| (url, breaker.retry_at().map_or(at, |retry| at.max(retry))))
+            .filter(|(_, at)| *at <= resync_at)
+        else {
+            tokio::select! {
+                () = token.cancelled() => break,
+                () = tokio::time::sleep_until(resync_at) => continue,
+            }
+        };
+
+        tokio::select! {
+            () = token.cancelled() => break,
+            () = tokio::time::sleep_until(at) => {}
+        }
+
+        if let Some(transition) = breaker.before_request(Instant::now()) {
+            record_transition(feed_loops.db(), &nitter, &transition).await;
+        }
+        schedule.record(&url, Instant::now());
+
+        let result = tokio::select! {
+            () = token.cancelled() => break,
+            result = feed_send(Arc::clone(&client), &feed_loops, &nitter, &url) => result,
+        };
+
+        // インスタンス自体の不調のみを数え、アカウント単位の失敗は応答があったものとみなす
+        let transition = match result.as_ref().err().and_then(|err| err.downcast_ref()) {
+            Some(err) => breaker.on_failure(err, Instant::now()),
+            None => breaker.on_success(),
+        };
+        if let Some(transition) = transition {
+            record_transition(feed_loops.db(), &nitter, &transition).await;
+        }
     }
 
     Ok(())
 }
-pub async fn feed_loop_nitter(client: Arc<SlackHyperClient>, nitter: String) -> anyhow::Result<()> {
-    loop {
-        let rss_urls = fetch_rss_urls(&nitter).await.unwrap_or_default();
 
-        let rss_urls_stream = futures::stream::iter(rss_urls);
+async fn feed_send(
+    client: Arc<SlackHyperClient>,
+    feed_loops: &FeedLoops,
+    nitter: &str,
+    url: &Url,
+) -> anyhow::Result<()> {
+    let db = feed_loops.db();
+    let (kind, remote_id) = query::fetch_feed_source(db, url).await?;
+    let source = feed_source::source(kind);
+    let account = source.account(url)?;
+    let fetch_url = source.fetch_url(url, nitter, &remote_id)?;
+    let cache = query::fetch_feed_cache(db, url).await?;
+
+    let fetched = async {
+        let Some((body, cache)) = fetch_rss(&fetch_url, &cache).await? else {
+            return anyhow::Ok(None);
+        };
+        let feed = source.parse(url, &body)?;
+        anyhow::Ok(Some((feed, cache)))
+    }
+    .await;
+    let fetched = match fetched {
+        Ok(fetched) => {
+            feed_loops.pool().record_success(nitter, url);
+            // 復旧の通知に失敗しても、取得できたフィードの配信は続ける
+            if let Err(err) = feed_health::record_success(db, &client, url, &account).await {
+                println!("failed to record feed recovery: {err:#?}");
+            }
+            fetched
+        }
+        // 切り替えや通知に失敗しても、元のエラーを返してサーキットブレーカーに取得の失敗を伝える
+        Err(err) => {
+            // 切り替え先の無い取得元 (RSS / Atom) はインスタンスの状態を記録しない
+            if source.can_failover() {
+                // アカウントが存在しない場合などはインスタンスの不調として数えない
+                if err.is::<InstanceError>() {
+                    feed_loops.pool().record_instance_failure(nitter);
+                }
+                if feed_loops.pool().record_feed_failure(url) {
+                    if let Err(failover_err) = failover(&client, feed_loops, nitter, url).await {
+                        println!("failed to fail over {url}: {failover_err:#?}");
+                    }
+                }
+            }
+            if let Err(alert_err) =
+                feed_health::record_failure(db, &client, url, &account, &err).await
+            {
+                println!("failed to record feed failure: {alert_err:#?}");
+            }
+            return Err(err);
+        }
+    };
+    // 304 Not Modified の場合はフィードを読まずに終える
+    let Some((feed, cache)) = fetched else {
+        return Ok(());
+    };
+    let ParsedFeed {
+        info,
+        entries,
+        last_date,
+    } = feed;
+    let (urls, channels) = fetch_twi_url(db, url, entries, last_date.as_deref()).await?;
+    let channel_tweets = filter_tweets(db, url, channels, &urls).await?;
+
+    send_message::send_to_channels(db, channel_tweets, client, info).await?;
+
+    // 配信済みの記録と送信を終えてから保存し、途中で失敗した場合は次回も本文を取得する
+    query::update_feed_cache(db, url, &cache).await
+}
+
+async fn record_transition(db: &SqlitePool, nitter: &str, transition: &Transition) {
+    let Transition { from, to, reason } = transition;
+    println!(
+        "circuit breaker {nitter}: {} -> {} ({reason})",
+        from.as_str(),
+        to.as_str()
+    );
+
+    if let Err(err) = query::insert_circuit_event(db, nitter, to.as_str(), reason).await {
+        println!("failed to record circuit event: {err:#?}");
+    }
+}
+
+// チャンネルごとに設定された条件でツイートを絞り込む
+async fn filter_tweets<'a>(
+    db: &SqlitePool,
+    rss_url: &Url,
+    channels: Vec<SlackChannelId>,
+    tweets: &'a [Tweet],
+) -> anyhow::Result<Vec<(SlackChannelId, Vec<&'a Tweet>)>> {
+    let mut channel_tweets = Vec::new();
+    for channel in channels {
+        let patterns = query::fetch_filters(db, rss_url, &channel).await?;
+        let options = query::fetch_options(db, rss_url, &channel).await?;
+        let filter = TweetFilter::new(&patterns, options)?;
 
-        rss_urls_stream
-            .filter_map(|url| async {
-                let cli = Arc::clone(&client);
-                async move { feed_send(cli, &url).await.ok() }.await
-            })
-            .collect::<()>()
-            .await;
+        let filtered = tweets
+            .iter()
+            .filter(|tweet| filter.is_match(tweet))
+            .collect::<Vec<_>>();
+        if !filtered.is_empty() {
+            channel_tweets.push((channel, filtered));
+        }
     }
+
+    Ok(channel_tweets)
 }
 
-async fn feed_send(client: Arc<SlackHyperClient>, url: &Url) -> anyhow::Result<()> {
-    tokio::time::sleep(Duration::from_secs(SLEEP_EACH_FETCH_MINUTES * 60)).await;
+// 取得に失敗し続けるアカウントを稼働中の別インスタンスへ移す
+async fn failover(
+    client: &Arc<SlackHyperClient>,
+    feed_loops: &FeedLoops,
+    nitter: &str,
+    url: &Url,
+) -> anyhow::Result<()> {
+    let _guard = feed_loops.lock().await;
+    let Some(next_nitter) = feed_loops.pool().healthy_nitter(nitter) else {
+        return Ok(());
+    };
+
+    query::update_nitter(feed_loops.db(), url, &next_nitter).await?;
+    feed_loops.pool().reset_feed(url);
+    feed_loops.start(client, &next_nitter);
+
+    let account = utils::url_to_account(url)?;
+    let channels = query::fetch_channels(feed_loops.db(), url).await?;
+    let text = format!("@{account} の取得元を {nitter} から {next_nitter} に切り替えました。");
+    // 通知に失敗しても、移動元のループの停止は行う
+    if let Err(err) = send_message::send_notice(channels, &text, Arc::clone(client)).await {
+        println!("failed to send failover notice: {err:#?}");
+    }
 
-    let account = utils::url_to_account(url)?.to_string();
+    // 移動元に購読が残っていなければ、このループ自身も停止する
+    let active_nitters = fetch_nitters(feed_loops.db()).await?;
+    feed_loops.stop_unused(&active_nitters);
 
-    let rss_channel = fetch_rss(url).await?;
-    let twi_info = get_twi_info(&rss_channel, account)?;
-    let (urls, channels) = fetch_twi_url(url, rss_channel).await?;
+    Ok(())
+}
 
-    send_message::send_to_channels(channels, &urls, client, twi_info).await
+// 応答の無いインスタンスで取得のループやコマンドが止まらないよう、時間を区切る
+// 接続を使い回すため、クライアントは 1 度だけ作る
+pub fn http_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(CONNECT_TIMEOUT_SECONDS))
+            .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECONDS))
+            .build()
+            .expect("failed to build the HTTP client")
+    })
 }
 
-async fn fetch_rss(nitter_rss_url: &Url) -> anyhow::Result<Channel> {
-    let raw_rss = reqwest::get(nitter_rss_url.clone()).await?;
+// ETag / Last-Modified を送り、更新が無ければ None を返す
+// 中身の解釈は取得元 (FeedSource) ごとに行う
+pub async fn fetch_rss(
+    nitter_rss_url: &Url,
+    cache: &FeedCache,
+) -> anyhow::Result<Option<(Vec<u8>, FeedCache)>> {
+    let mut req = http_client().get(nitter_rss_url.clone());
+    if !cache.etag.is_empty() {
+        req = req.header(IF_NONE_MATCH, &cache.etag);
+    }
+    if !cache.last_modified.is_empty() {
+        req = req.header(IF_MODIFIED_SINCE, &cache.last_modified);
+    }
+
+    let raw_rss = req
+        .send()
+        .await
+        .map_err(|err| InstanceError::transport(&err))?;
     //println!("request occured. url: {:#?}", nitter_rss_url.domain());
     //utils::_print_datetime();
 
-    let rss_bytes = raw_rss.bytes().await?;
+    if raw_rss.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    if let Some(err) = InstanceError::from_response(&raw_rss) {
+        return Err(err.into());
+    }
+    let raw_rss = raw_rss.error_for_status()?;
+
+    let header_str = |name| {
+        raw_rss
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let new_cache = FeedCache {
+        etag: header_str(ETAG),
+        last_modified: header_str(LAST_MODIFIED),
+    };
 
-    let channel = Channel::read_from(&rss_bytes[..])?;
+    let rss_bytes = raw_rss
+        .bytes()
+        .await
+        .map_err(|err| InstanceError::transport(&err))?;
 
-    Ok(channel)
+    Ok(Some((rss_bytes.to_vec(), new_cache)))
 }
 async fn fetch_twi_url(
+    db: &SqlitePool,
     nitter_rss_url: &Url,
-    rss_channel: Channel,
+    entries: Vec<Entry>,
+    last_date: Option<&str>,
 ) -> anyhow::Result<(Vec<Tweet>, Vec<SlackChannelId>)> {
-    let items = rss_channel.items().to_vec();
+    let status_ids = entries.iter().map(|e| e.id.clone()).collect::<Vec<_>>();
 
-    let last_date_rss = last_update(&items)?;
-
-    let last_date_db = query::fetch_last_date(nitter_rss_url).await?;
-    if last_date_db == last_date_rss {
-        //println!("no update");
-        return Err(anyhow::anyhow!("No update"));
-    };
+    let delivered_ids = query::fetch_delivered_ids(db, nitter_rss_url).await?;
 
-    let updated_tweets = if last_date_db.is_empty() {
+    // 初回取得時は既存のツイートを配信済みとして記録するのみ
+    let updated_tweets = if delivered_ids.is_empty() {
         Vec::default()
     } else {
-        updated_tweets(items, &last_date_db)
+        updated_tweets(entries, &delivered_ids)
     };
 
-    query::update_last_date(nitter_rss_url, &last_date_rss).await?;
+    query::upsert_delivered_ids(db, nitter_rss_url, &status_ids).await?;
+    if let Some(last_date) = last_date {
+        query::update_last_date(db, nitter_rss_url, last_date).await?;
+    }
+
+    // 更新が無くてもエラーにはせず、キャッシュの更新まで進める
+    if updated_tweets.is_empty() {
+        //println!("no update");
+        return Ok((updated_tweets, Vec::new()));
+    }
 
-    let feed_channels = query::fetch_channels(nitter_rss_url).await?;
+    let feed_channels = query::fetch_channels(db, nitter_rss_url).await?;
 
     Ok((updated_tweets, feed_channels))
 }
 
 #[derive(Debug)]
 pub struct TwiInfo {
-    pub icon_url: Url,
+    pub icon_url: Option<Url>,
     pub display_name: String,
     pub account: String,
+    pub source: SourceKind,
+    pub profile_url: Option<Url>,
 }
-fn get_twi_info(rss_channel: &Channel, account: String) -> anyhow::Result<TwiInfo> {
+pub fn get_twi_info(rss_channel: &Channel, account: String) -> anyhow::Result<TwiInfo> {
     let display_name_raw = rss_channel.title();
     let display_name = utils::validate_display_name(display_name_raw, &account);
     let icon_url_str = rss_channel.image().context("invalid input")?.url();
     let icon_url = url::Url::parse(icon_url_str)?;
 
+    let profile_url = utils::account_to_twitter_profile(&account).ok();
+
     Ok(TwiInfo {
-        icon_url,
+        icon_url: Some(icon_url),
         display_name,
         account,
+        source: SourceKind::Nitter,
+        profile_url,
     })
 }
 #[derive(Debug)]
 pub struct Tweet {
     pub twi_url: Url,
+    pub nitter_url: Url,
+    // RSS / Atom フィードの項目のタイトル (ツイートには無い)
+    pub title: Option<String>,
+    // 投稿者のアカウント (リツイートの場合は元の投稿者)
+    pub author: String,
+    // 元の投稿者の表示名とアイコン (取得元が提供する場合のみ)
+    pub author_name: Option<String>,
+    pub author_icon: Option<Url>,
+    pub text: String,
+    // 閲覧注意 (Mastodon の CW) の警告文
+    pub content_warning: Option<String>,
+    pub pub_date: Option<String>,
     pub pics: Vec<Url>,
+    pub videos: Vec<Video>,
+    pub is_reply: bool,
+    pub is_retweet: bool,
+    pub quote_url: Option<Url>,
+    pub entities: TweetEntities,
+}
+
+// 本文中のメンションやリンクなど (Nitter のツイートのみ)
+#[derive(Debug, Default)]
+pub struct TweetEntities {
+    pub mentions: Vec<String>,
+    pub hashtags: Vec<String>,
+    // 外部へのリンク (最後のものがカードとして表示される)
+    pub links: Vec<Url>,
+}
+
+// 動画や GIF (Slack では再生できないため、サムネイルとリンクを載せる)
+#[derive(Debug, PartialEq, Eq)]
+pub struct Video {
+    // video.twimg.com の動画 (分からなければツイートへのリンクで代える)
+    pub url: Option<Url>,
+    pub thumb: Option<Url>,
+    pub is_gif: bool,
 }
-fn updated_tweets(items: Vec<Item>, last_date: &str) -> std::vec::Vec<Tweet> {
-    let updated_items = items
+// 未配信のものを古い順に返す
+fn updated_tweets(entries: Vec<Entry>, delivered_ids: &HashSet<String>) -> Vec<Tweet> {
+    let updated_items = entries
         .into_iter()
-        .take_while(|Item { pub_date, .. }| {
-            pub_date.as_ref().map(std::string::String::as_str) != Some(last_date)
-        })
-        .filter_map(
-            |Item {
-                 link, description, ..
-             }| {
-                let url_opt = link
-                    .and_then(|s| Url::parse(&s).ok())
-                    .and_then(|url| utils::nitter_url_to_twi(&url).ok());
-                if let Some(url) = url_opt {
-                    let pics = description.map_or(Vec::default(), |des| fetch_twi_images(&des));
-                    Some(Tweet { twi_url: url, pics })
-                } else {
-                    None
-                }
-            },
-        )
+        .filter(|entry| !delivered_ids.contains(&entry.id))
+        .map(|entry| entry.tweet)
         .collect::<Vec<_>>();
     updated_items.into_iter().rev().collect::<Vec<_>>()
 }
-fn last_update(items: &[Item]) -> anyhow::Result<String> {
-    let last_date = items
-        .get(0)
-        .context("no item")?
-        .pub_date()
-        .context("no pub date")?
-        .to_string();
-    Ok(last_date)
-}
-
-pub fn fetch_twi_images(description: &str) -> Vec<Url> {
-    let nitter_imgs = fetch_nitter_images(description);
-    let twi_pic = Url::parse("https://pbs.twimg.com/").unwrap();
-
-    let twi_imgs = nitter_imgs
-        .iter()
-        .filter_map(|nit_url| {
-            let id_opt = utils::get_image_id(nit_url);
-            id_opt.and_then(|id| twi_pic.join(&id).ok())
+
+// Nitter の RSS (https://{nitter}/{account}/rss)
+pub struct NitterSource;
+
+impl FeedSource for NitterSource {
+    fn fetch_url(&self, rss_url: &Url, host: &str, _remote_id: &str) -> anyhow::Result<Url> {
+        utils::rss_url_on_nitter(rss_url, host)
+    }
+
+    fn account(&self, rss_url: &Url) -> anyhow::Result<String> {
+        Ok(utils::url_to_account(rss_url)?.to_string())
+    }
+
+    fn parse(&self, rss_url: &Url, body: &[u8]) -> anyhow::Result<ParsedFeed> {
+        let channel = Channel::read_from(body)?;
+        let account = self.account(rss_url)?;
+        let info = get_twi_info(&channel, account)?;
+        let last_date = channel
+            .items()
+            .first()
+            .and_then(|item| item.pub_date())
+            .map(str::to_string);
+        let entries = nitter_entries(channel.into_items(), &info.account);
+
+        Ok(ParsedFeed {
+            info,
+            entries,
+            last_date,
+        })
+    }
+
+    fn can_failover(&self) -> bool {
+        true
+    }
+}
+
+fn nitter_entries(items: Vec<Item>, account: &str) -> Vec<Entry> {
+    items
+        .into_iter()
+        .filter_map(|item| {
+            let id = item_status_id(&item)?;
+            let Item {
+                title,
+                link,
+                description,
+                pub_date,
+                ..
+            } = item;
+            let nitter_url = link.and_then(|s| Url::parse(&s).ok())?;
+            let twi_url = utils::nitter_url_to_twi(&nitter_url).ok()?;
+            let description = description.unwrap_or_default();
+            let TweetContent {
+                text,
+                pics,
+                videos,
+                quote_url,
+                entities,
+            } = tweet_content::parse(&description, &nitter_url);
+            // Nitter はリプライのタイトルを "R to @account: " で始める
+            let is_reply = title.is_some_and(|t| t.starts_with("R to @"));
+            let is_retweet = utils::is_retweet(&twi_url, account);
+            let author = utils::url_to_account(&twi_url)
+                .unwrap_or(account)
+                .to_lowercase();
+            let tweet = Tweet {
+                twi_url,
+                nitter_url,
+                title: None,
+                author,
+                author_name: None,
+                author_icon: None,
+                text,
+                content_warning: None,
+                pub_date,
+                pics,
+                videos,
+                is_reply,
+                is_retweet,
+                quote_url,
+                entities,
+            };
+            Some(Entry { id, tweet })
         })
-        .collect::<Vec<_>>();
-    twi_imgs
+        .collect()
+}
+// Nitter のリンク、無ければ guid からツイートの ID を取り出す
+fn item_status_id(item: &Item) -> Option<String> {
+    item.link()
+        .and_then(utils::status_id)
+        .or_else(|| item.guid().and_then(|guid| utils::status_id(guid.value())))
 }
 
-pub fn fetch_nitter_images(description: &str) -> Vec<Url> {
-    let fragment = Html::parse_document(description);
-    let selector = Selector::parse("img[src]").unwrap();
+pub fn fetch_tweet_text(description: &str) -> String {
+    let fragment = Html::parse_fragment(description);
+    let text = fragment.root_element().text().collect::<String>();
+    text.trim().to_string()
+}
 
-    let urls = fragment
-        .select(&selector)
-        .map(|elem| {
-            elem.value()
-                .attr("src")
-                .map(|url_str| url_str.replace("%2F", "/"))
-        })
-        .filter_map(|src| src.and_then(|url| Url::parse(&url).ok()))
-        .collect::<Vec<_>>();
+#[cfg(test)]
+mod tests {
+    use rss::ItemBuilder;
+
+    use super::*;
+
+    #[test]
+    fn updated_tweets_test() {
+        let item = |id: &str| {
+            ItemBuilder::default()
+                .link(format!("https://nitter.net/test/status/{id}#m"))
+                .build()
+        };
+        // 固定ツイートが先頭にあっても、未配信のツイートのみを古い順に返す
+        let items = vec![item("100"), item("300"), item("200"), item("150")];
+        let delivered_ids = HashSet::from(["100".to_string(), "150".to_string()]);
 
-    urls
+        let tweets = updated_tweets(nitter_entries(items, "test"), &delivered_ids)
+            .into_iter()
+            .map(|t| t.twi_url.to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                "https://twitter.com/test/status/200",
+                "https://twitter.com/test/status/300",
+            ],
+            tweets
+        );
+    }
 }
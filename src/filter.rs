@@ -0,0 +1,201 @@
+use std::{fmt, str::FromStr};
+
+use regex::Regex;
+use sqlx::FromRow;
+
+use crate::fetch_rss::Tweet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterKind {
+    Include,
+    Exclude,
+}
+
+impl FilterKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Include => "include",
+            Self::Exclude => "exclude",
+        }
+    }
+}
+
+impl FromStr for FilterKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "include" => Ok(Self::Include),
+            "exclude" => Ok(Self::Exclude),
+            _ => Err(anyhow::anyhow!("unknown filter kind: {s}")),
+        }
+    }
+}
+
+// 購読 (rss_url, channel) ごとに、送信するツイートの種類
+#[allow(clippy::struct_field_names)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromRow)]
+pub struct SubscriptionOptions {
+    pub include_retweets: bool,
+    pub include_replies: bool,
+    pub include_quotes: bool,
+}
+
+impl Default for SubscriptionOptions {
+    fn default() -> Self {
+        Self {
+            include_retweets: true,
+            include_replies: true,
+            include_quotes: true,
+        }
+    }
+}
+
+impl SubscriptionOptions {
+    // expected input: --retweets, --no-replies など
+    pub fn apply_flags(&mut self, flags: &[&str]) -> anyhow::Result<()> {
+        for flag in flags {
+            let (name, value) = flag
+                .strip_prefix("--no-")
+                .map(|name| (name, false))
+                .or_else(|| flag.strip_prefix("--").map(|name| (name, true)))
+                .ok_or_else(|| anyhow::anyhow!("unknown option: {flag}"))?;
+
+            match name {
+                "retweets" => self.include_retweets = value,
+                "replies" => self.include_replies = value,
+                "quotes" => self.include_quotes = value,
+                _ => return Err(anyhow::anyhow!("unknown option: {flag}")),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SubscriptionOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mark = |include: bool| if include { "○" } else { "×" };
+        write!(
+            f,
+            "リツイート: {} / リプライ: {} / 引用: {}",
+            mark(self.include_retweets),
+            mark(self.include_replies),
+            mark(self.include_quotes)
+        )
+    }
+}
+
+// 購読 (rss_url, channel) ごとに設定された絞り込み条件
+#[derive(Debug, Default)]
+pub struct TweetFilter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    options: SubscriptionOptions,
+}
+
+impl TweetFilter {
+    pub fn new(
+        patterns: &[(FilterKind, String)],
+        options: SubscriptionOptions,
+    ) -> anyhow::Result<Self> {
+        let mut filter = Self {
+            options,
+            ..Self::default()
+        };
+        for (kind, pattern) in patterns {
+            let re = Regex::new(pattern)?;
+            match kind {
+                FilterKind::Include => filter.include.push(re),
+                FilterKind::Exclude => filter.exclude.push(re),
+            }
+        }
+        Ok(filter)
+    }
+
+    // include が設定されていればいずれかに一致し、かつ exclude のどれにも一致しないもの
+    pub fn is_match(&self, tweet: &Tweet) -> bool {
+        let SubscriptionOptions {
+            include_retweets,
+            include_replies,
+            include_quotes,
+        } = self.options;
+        if (!include_retweets && tweet.is_retweet)
+            || (!include_replies && tweet.is_reply)
+            || (!include_quotes && tweet.quote_url.is_some())
+        {
+            return false;
+        }
+
+        let text = &tweet.text;
+        let included = self.include.is_empty() || self.include.iter().any(|re| re.is_match(text));
+        let excluded = self.exclude.iter().any(|re| re.is_match(text));
+
+        included && !excluded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+    use crate::fetch_rss::TweetEntities;
+
+    fn tweet(text: &str) -> Tweet {
+        Tweet {
+            twi_url: Url::parse("https://twitter.com/test/status/0000").unwrap(),
+            nitter_url: Url::parse("https://nitter.net/test/status/0000#m").unwrap(),
+            title: None,
+            author: "test".to_string(),
+            author_name: None,
+            author_icon: None,
+            content_warning: None,
+            text: text.to_string(),
+            pub_date: None,
+            pics: Vec::default(),
+            videos: Vec::new(),
+            is_reply: false,
+            is_retweet: false,
+            quote_url: None,
+            entities: TweetEntities::default(),
+        }
+    }
+
+    #[test]
+    fn is_match_test() {
+        let filter = TweetFilter::new(
+            &[
+                (FilterKind::Include, "(?i)rust".to_string()),
+                (FilterKind::Include, "リリース".to_string()),
+                (FilterKind::Exclude, "PR$".to_string()),
+            ],
+            SubscriptionOptions::default(),
+        )
+        .unwrap();
+
+        assert!(filter.is_match(&tweet("Rust 1.70 is out")));
+        assert!(filter.is_match(&tweet("新バージョンをリリースしました")));
+        assert!(!filter.is_match(&tweet("rust conf PR")));
+        assert!(!filter.is_match(&tweet("hello")));
+        assert!(TweetFilter::default().is_match(&tweet("hello")));
+    }
+
+    #[test]
+    fn options_test() {
+        let other_tweet = |is_retweet| Tweet {
+            twi_url: Url::parse("https://twitter.com/other/status/0000").unwrap(),
+            nitter_url: Url::parse("https://nitter.net/other/status/0000#m").unwrap(),
+            is_retweet,
+            ..tweet("")
+        };
+        let mut options = SubscriptionOptions::default();
+        options
+            .apply_flags(&["--no-retweets", "--no-quotes"])
+            .unwrap();
+        let filter = TweetFilter::new(&[], options).unwrap();
+
+        assert!(!filter.is_match(&other_tweet(true)));
+        assert!(filter.is_match(&other_tweet(false)));
+        assert!(options.apply_flags(&["--no-likes"]).is_err());
+    }
+}
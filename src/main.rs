@@ -1,23 +1,41 @@
 #![warn(clippy::pedantic)]
 
+mod bluesky;
+mod circuit_breaker;
+mod command_error;
 mod command_event_handler;
+mod feed_health;
+mod feed_loops;
+mod feed_source;
 mod fetch_rss;
+mod filter;
+mod mastodon;
+mod migration;
+mod nitter_pool;
 mod query;
+mod render;
+mod scheduler;
 mod send_message;
+mod tweet_content;
 mod utils;
 
+use feed_loops::FeedLoops;
 use fetch_rss::feed_loop;
+use nitter_pool::NitterPool;
 use slack_morphism::prelude::*;
 use std::sync::Arc;
 
 async fn socket_mode_process(
     client: Arc<SlackHyperClient>,
     app_token: Arc<SlackApiToken>,
+    feed_loops: FeedLoops,
 ) -> anyhow::Result<()> {
     let socket_mode_callbacks = SlackSocketModeListenerCallbacks::new()
         .with_command_events(command_event_handler::command_event_handler);
     let listner_environment = Arc::new(
-        SlackClientEventsListenerEnvironment::new(client.clone()).with_error_handler(error_handler),
+        SlackClientEventsListenerEnvironment::new(client.clone())
+            .with_error_handler(error_handler)
+            .with_user_state(feed_loops),
     );
     let socket_mode_listner = SlackClientSocketModeListener::new(
         &SlackClientSocketModeConfig::new(),
@@ -42,14 +60,16 @@ fn error_handler(
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    query::setup_db().await?;
+    let db = query::setup_db(&query::db_url_from_env()).await?;
 
     let app_token = Arc::new(utils::get_token(&SlackApiTokenType::App)?);
     let client = Arc::new(SlackClient::new(SlackClientHyperConnector::new()));
 
-    tokio::spawn(feed_loop(client.clone()));
+    let feed_loops = FeedLoops::new(NitterPool::from_env()?, db);
 
-    socket_mode_process(Arc::clone(&client), Arc::clone(&app_token)).await?;
+    tokio::spawn(feed_loop(client.clone(), feed_loops.clone()));
+
+    socket_mode_process(Arc::clone(&client), Arc::clone(&app_token), feed_loops).await?;
 
     Ok(())
 }
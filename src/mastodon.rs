@@ -0,0 +1,262 @@
+use serde::Deserialize;
+use url::Url;
+
+use crate::{
+    feed_source::{Entry, FeedSource, ParsedFeed, SourceKind},
+    fetch_rss::{self, Tweet, TweetEntities, TwiInfo},
+    utils,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct Account {
+    pub id: String,
+    pub acct: String,
+    pub display_name: String,
+    pub avatar: String,
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Status {
+    id: String,
+    uri: String,
+    url: Option<String>,
+    created_at: String,
+    content: String,
+    // 空文字列でなければ閲覧注意 (CW) の警告文
+    spoiler_text: String,
+    in_reply_to_id: Option<String>,
+    // ブースト (Twitter のリツイートに相当) であれば、ブーストされた投稿
+    reblog: Option<Box<Status>>,
+    media_attachments: Vec<Attachment>,
+    account: Account,
+}
+
+#[derive(Debug, Deserialize)]
+struct Attachment {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    preview_url: Option<String>,
+}
+
+// expected input: @user@mastodon.social, https://mastodon.social/@user, https://mastodon.social/@user.rss
+// expected output: https://mastodon.social/@user.rss
+pub fn rss_url(input: &str) -> Option<Url> {
+    let (user, host) = if input.contains("://") {
+        let url = Url::parse(input).ok()?;
+        let user = url.path().strip_prefix("/@")?.trim_end_matches('/');
+        let user = user.strip_suffix(".rss").unwrap_or(user);
+        (user.to_string(), url.host_str()?.to_string())
+    } else {
+        let (user, host) = input.trim_start_matches('@').split_once('@')?;
+        (user.to_string(), host.to_string())
+    };
+    if user.is_empty() || user.contains(['/', '@']) || host.is_empty() {
+        return None;
+    }
+
+    Url::parse(&format!("https://{host}/@{user}.rss"))
+        .ok()
+        .map(|url| utils::canonical_rss_url(&url))
+}
+
+// 投稿の取得に使うアカウント ID を調べる
+pub async fn lookup_account(rss_url: &Url, acct: &str) -> anyhow::Result<Account> {
+    let mut lookup_url = rss_url.join("/api/v1/accounts/lookup")?;
+    lookup_url.query_pairs_mut().append_pair("acct", acct);
+
+    let body = fetch_rss::http_client()
+        .get(lookup_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    let account = serde_json::from_slice::<Account>(&body)?;
+    Ok(account)
+}
+
+// Mastodon のアカウント (https://{host}/@{user}.rss をキーに、公開 API から取得する)
+// RSS にはブーストや CW が含まれないため、API の JSON を読む
+pub struct MastodonSource;
+
+impl FeedSource for MastodonSource {
+    fn fetch_url(&self, rss_url: &Url, _host: &str, remote_id: &str) -> anyhow::Result<Url> {
+        let statuses_url = rss_url.join(&format!("/api/v1/accounts/{remote_id}/statuses"))?;
+        Ok(statuses_url)
+    }
+
+    // expected output: user@mastodon.social
+    fn account(&self, rss_url: &Url) -> anyhow::Result<String> {
+        let user = rss_url
+            .path()
+            .strip_prefix("/@")
+            .and_then(|path| path.strip_suffix(".rss"))
+            .ok_or_else(|| anyhow::anyhow!("invalid mastodon url: {rss_url}"))?;
+        let host = rss_url
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("invalid mastodon url: {rss_url}"))?;
+        Ok(format!("{user}@{host}").to_lowercase())
+    }
+
+    fn parse(&self, rss_url: &Url, body: &[u8]) -> anyhow::Result<ParsedFeed> {
+        let statuses = serde_json::from_slice::<Vec<Status>>(body)?;
+        let account = self.account(rss_url)?;
+
+        // 投稿が無ければアカウントの情報も得られないため、アカウント名のみを使う
+        let info = statuses.first().map_or_else(
+            || TwiInfo {
+                icon_url: None,
+                display_name: account.clone(),
+                account: account.clone(),
+                source: SourceKind::Mastodon,
+                profile_url: None,
+            },
+            |status| account_info(&status.account, account.clone()),
+        );
+        let last_date = statuses.first().and_then(|s| utils::rfc2822(&s.created_at));
+        let entries = statuses
+            .into_iter()
+            .filter_map(|status| {
+                let id = status.id.clone();
+                let tweet = status_tweet(status, rss_url)?;
+                Some(Entry { id, tweet })
+            })
+            .collect();
+
+        Ok(ParsedFeed {
+            info,
+            entries,
+            last_date,
+        })
+    }
+}
+
+pub fn account_info(mastodon_account: &Account, account: String) -> TwiInfo {
+    // 表示名が未設定の場合は空文字列になる
+    let display_name = Some(mastodon_account.display_name.trim())
+        .filter(|name| !name.is_empty())
+        .unwrap_or(&account)
+        .to_string();
+    TwiInfo {
+        icon_url: Url::parse(&mastodon_account.avatar).ok(),
+        display_name,
+        account,
+        source: SourceKind::Mastodon,
+        profile_url: Url::parse(&mastodon_account.url).ok(),
+    }
+}
+
+fn status_tweet(status: Status, rss_url: &Url) -> Option<Tweet> {
+    let (is_retweet, post) = match status.reblog {
+        Some(reblog) => (true, *reblog),
+        None => (false, status),
+    };
+
+    let post_url = Url::parse(post.url.as_deref().unwrap_or(&post.uri)).ok()?;
+    // 他のサーバーのアカウントは acct が user@host になる
+    let author = if post.account.acct.contains('@') {
+        post.account.acct.to_lowercase()
+    } else {
+        format!("{}@{}", post.account.acct, rss_url.host_str()?).to_lowercase()
+    };
+    let pics = post
+        .media_attachments
+        .iter()
+        .filter_map(|media| match media.kind.as_str() {
+            "image" => Url::parse(&media.url).ok(),
+            // 動画や GIF はサムネイルを載せる
+            _ => media
+                .preview_url
+                .as_deref()
+                .and_then(|url| Url::parse(url).ok()),
+        })
+        .collect();
+    let content_warning = Some(post.spoiler_text.trim().to_string()).filter(|cw| !cw.is_empty());
+
+    Some(Tweet {
+        twi_url: post_url.clone(),
+        nitter_url: post_url,
+        title: None,
+        author,
+        author_name: Some(post.account.display_name.trim().to_string())
+            .filter(|name| !name.is_empty()),
+        author_icon: Url::parse(&post.account.avatar).ok(),
+        text: content_text(&post.content),
+        content_warning,
+        pub_date: utils::rfc2822(&post.created_at),
+        pics,
+        videos: Vec::new(),
+        is_reply: post.in_reply_to_id.is_some(),
+        is_retweet,
+        quote_url: None,
+        entities: TweetEntities::default(),
+    })
+}
+
+// 段落と改行を残してタグを取り除く
+fn content_text(content: &str) -> String {
+    let content = content
+        .replace("</p><p>", "\n\n")
+        .replace("<br>", "\n")
+        .replace("<br />", "\n");
+    fetch_rss::fetch_tweet_text(&content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_statuses_test() {
+        let account = r#"{"id": "1", "acct": "user", "display_name": "User", "avatar": "https://mastodon.social/avatar.png", "url": "https://mastodon.social/@user"}"#;
+        let original = r#"{"id": "2", "acct": "other@example.com", "display_name": "", "avatar": "https://example.com/avatar.png", "url": "https://example.com/@other"}"#;
+        let body = format!(
+            r#"[
+  {{"id": "110", "uri": "https://mastodon.social/users/user/statuses/110/activity", "url": null,
+    "created_at": "2023-06-02T00:00:00.000Z", "content": "", "spoiler_text": "", "in_reply_to_id": null,
+    "media_attachments": [], "account": {account},
+    "reblog": {{"id": "90", "uri": "https://example.com/users/other/statuses/90", "url": "https://example.com/@other/90",
+      "created_at": "2023-06-01T00:00:00.000Z", "content": "<p>boosted</p>", "spoiler_text": "", "in_reply_to_id": null,
+      "media_attachments": [{{"type": "gifv", "url": "https://example.com/a.mp4", "preview_url": "https://example.com/a.png"}}],
+      "account": {original}, "reblog": null}}}},
+  {{"id": "100", "uri": "https://mastodon.social/users/user/statuses/100", "url": "https://mastodon.social/@user/100",
+    "created_at": "2023-06-01T00:00:00.000Z", "content": "<p>line 1<br>line 2</p><p>spoiler</p>", "spoiler_text": "ネタバレ",
+    "in_reply_to_id": null, "media_attachments": [], "account": {account}, "reblog": null}}
+]"#
+        );
+        let rss_url = rss_url("@User@mastodon.social").unwrap();
+        assert_eq!("https://mastodon.social/@user.rss", rss_url.as_str());
+
+        let feed = MastodonSource.parse(&rss_url, body.as_bytes()).unwrap();
+
+        assert_eq!("User", feed.info.display_name);
+        assert_eq!("user@mastodon.social", feed.info.account);
+
+        let boost = &feed.entries[0];
+        assert_eq!("110", boost.id);
+        assert!(boost.tweet.is_retweet);
+        assert_eq!("other@example.com", boost.tweet.author);
+        // 表示名が未設定の場合はアカウント名のみを表示する
+        assert_eq!(None, boost.tweet.author_name);
+        assert_eq!(
+            Some("https://example.com/avatar.png"),
+            boost.tweet.author_icon.as_ref().map(Url::as_str)
+        );
+        assert_eq!(
+            "https://example.com/@other/90",
+            boost.tweet.twi_url.as_str()
+        );
+        assert_eq!(
+            vec!["https://example.com/a.png"],
+            boost.tweet.pics.iter().map(Url::as_str).collect::<Vec<_>>()
+        );
+
+        let post = &feed.entries[1].tweet;
+        assert!(!post.is_retweet);
+        assert_eq!("user@mastodon.social", post.author);
+        assert_eq!("line 1\nline 2\n\nspoiler", post.text);
+        assert_eq!(Some("ネタバレ"), post.content_warning.as_deref());
+    }
+}
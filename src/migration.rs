@@ -0,0 +1,402 @@
+use sqlx::{SqliteConnection, SqlitePool};
+
+// 適用済みのマイグレーションを記録する
+const SCHEMA_VERSION_TABLE: &str = "CREATE TABLE IF NOT EXISTS schema_version
+(
+    version INTEGER NOT NULL PRIMARY KEY,
+    description TEXT NOT NULL,
+    applied_at TEXT NOT NULL
+);";
+
+enum Step {
+    Sql(&'static str),
+    // 既存の DB に後から追加したカラムを補う
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        definition: &'static str,
+    },
+}
+
+struct Migration {
+    version: i64,
+    description: &'static str,
+    steps: &'static [Step],
+}
+
+// 新しいスキーマの変更は、version を 1 つ増やして末尾に追加する
+const MIGRATIONS: [Migration; 4] = [
+    // 3 テーブル (last_item, feed_channel, nitter_instance) のみの DB もそのまま引き継ぐ
+    Migration {
+        version: 1,
+        description: "upgrade the three-table layout",
+        steps: &[
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS last_item
+(
+    rss_url TEXT NOT NULL PRIMARY KEY,
+    account TEXT NOT NULL,
+    date TEXT NOT NULL DEFAULT '',
+    etag TEXT NOT NULL DEFAULT '',
+    last_modified TEXT NOT NULL DEFAULT ''
+);",
+            ),
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS feed_channel
+(
+    rss_url TEXT NOT NULL,
+    channel TEXT NOT NULL,
+    added_at TEXT NOT NULL DEFAULT '',
+    include_retweets INTEGER NOT NULL DEFAULT 1,
+    include_replies INTEGER NOT NULL DEFAULT 1,
+    include_quotes INTEGER NOT NULL DEFAULT 1,
+    interval_minutes INTEGER NOT NULL DEFAULT 5,
+    PRIMARY KEY (rss_url, channel)
+);",
+            ),
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS nitter_instance
+(
+    rss_url TEXT NOT NULL PRIMARY KEY,
+    nitter TEXT NOT NULL,
+    previous_nitter TEXT NOT NULL DEFAULT '',
+    FOREIGN KEY (rss_url) REFERENCES last_item(rss_url) ON DELETE CASCADE
+);",
+            ),
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS delivered_tweet
+(
+    rss_url TEXT NOT NULL,
+    status_id TEXT NOT NULL,
+    last_seen TEXT NOT NULL DEFAULT '',
+    PRIMARY KEY (rss_url, status_id),
+    FOREIGN KEY (rss_url) REFERENCES last_item(rss_url) ON DELETE CASCADE
+);",
+            ),
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS channel_setting
+(
+    channel TEXT NOT NULL PRIMARY KEY,
+    layout TEXT NOT NULL DEFAULT 'link',
+    images TEXT NOT NULL DEFAULT 'blocks'
+);",
+            ),
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS tweet_filter
+(
+    rss_url TEXT NOT NULL,
+    channel TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    pattern TEXT NOT NULL,
+    PRIMARY KEY (rss_url, channel, kind, pattern),
+    FOREIGN KEY (rss_url, channel) REFERENCES feed_channel(rss_url, channel) ON DELETE CASCADE
+);",
+            ),
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS circuit_event
+(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    nitter TEXT NOT NULL,
+    state TEXT NOT NULL,
+    reason TEXT NOT NULL DEFAULT '',
+    changed_at TEXT NOT NULL DEFAULT ''
+);",
+            ),
+            Step::Sql(
+                "CREATE TABLE IF NOT EXISTS feed_health
+(
+    rss_url TEXT NOT NULL PRIMARY KEY,
+    failures INTEGER NOT NULL DEFAULT 0,
+    failing_since TEXT NOT NULL,
+    last_error TEXT NOT NULL DEFAULT '',
+    alerted INTEGER NOT NULL DEFAULT 0,
+    FOREIGN KEY (rss_url) REFERENCES last_item(rss_url) ON DELETE CASCADE
+);",
+            ),
+            Step::AddColumn {
+                table: "last_item",
+                column: "etag",
+                definition: "TEXT NOT NULL DEFAULT ''",
+            },
+            Step::AddColumn {
+                table: "last_item",
+                column: "last_modified",
+                definition: "TEXT NOT NULL DEFAULT ''",
+            },
+            Step::AddColumn {
+                table: "feed_channel",
+                column: "added_at",
+                definition: "TEXT NOT NULL DEFAULT ''",
+            },
+            Step::AddColumn {
+                table: "feed_channel",
+                column: "include_retweets",
+                definition: "INTEGER NOT NULL DEFAULT 1",
+            },
+            Step::AddColumn {
+                table: "feed_channel",
+                column: "include_replies",
+                definition: "INTEGER NOT NULL DEFAULT 1",
+            },
+            Step::AddColumn {
+                table: "feed_channel",
+                column: "include_quotes",
+                definition: "INTEGER NOT NULL DEFAULT 1",
+            },
+            Step::AddColumn {
+                table: "nitter_instance",
+                column: "previous_nitter",
+                definition: "TEXT NOT NULL DEFAULT ''",
+            },
+            Step::AddColumn {
+                table: "channel_setting",
+                column: "images",
+                definition: "TEXT NOT NULL DEFAULT 'blocks'",
+            },
+            Step::AddColumn {
+                table: "feed_channel",
+                column: "interval_minutes",
+                definition: "INTEGER NOT NULL DEFAULT 5",
+            },
+        ],
+    },
+    // rss_url を小文字にし、末尾の / とクエリを取り除く (utils::canonical_rss_url と同じ)
+    // 正規化すると同じになる購読は 1 つにまとめる
+    Migration {
+        version: 2,
+        description: "canonicalize rss urls and accounts",
+        steps: &[
+            Step::Sql(
+                "CREATE TEMP TABLE canonical_url AS
+SELECT rss_url AS old_url,
+    rtrim(lower(CASE WHEN instr(rss_url, '?') > 0
+        THEN substr(rss_url, 1, instr(rss_url, '?') - 1)
+        ELSE rss_url END), '/') AS new_url
+FROM last_item;",
+            ),
+            Step::Sql("DELETE FROM canonical_url WHERE old_url = new_url;"),
+            Step::Sql(
+                "INSERT OR IGNORE INTO last_item (rss_url, account, date)
+SELECT c.new_url, li.account, li.date
+FROM last_item li INNER JOIN canonical_url c ON li.rss_url = c.old_url;",
+            ),
+            Step::Sql(
+                "INSERT OR IGNORE INTO nitter_instance (rss_url, nitter, previous_nitter)
+SELECT c.new_url, ni.nitter, ni.previous_nitter
+FROM nitter_instance ni INNER JOIN canonical_url c ON ni.rss_url = c.old_url;",
+            ),
+            Step::Sql(
+                "INSERT OR IGNORE INTO delivered_tweet (rss_url, status_id, last_seen)
+SELECT c.new_url, dt.status_id, dt.last_seen
+FROM delivered_tweet dt INNER JOIN canonical_url c ON dt.rss_url = c.old_url;",
+            ),
+            Step::Sql(
+                "INSERT OR IGNORE INTO feed_channel
+    (rss_url, channel, added_at, include_retweets, include_replies, include_quotes,
+    interval_minutes)
+SELECT c.new_url, fc.channel, fc.added_at, fc.include_retweets, fc.include_replies,
+    fc.include_quotes, fc.interval_minutes
+FROM feed_channel fc INNER JOIN canonical_url c ON fc.rss_url = c.old_url;",
+            ),
+            Step::Sql(
+                "INSERT OR IGNORE INTO tweet_filter (rss_url, channel, kind, pattern)
+SELECT c.new_url, tf.channel, tf.kind, tf.pattern
+FROM tweet_filter tf INNER JOIN canonical_url c ON tf.rss_url = c.old_url;",
+            ),
+            Step::Sql(
+                "DELETE FROM tweet_filter WHERE rss_url IN (SELECT old_url FROM canonical_url);",
+            ),
+            Step::Sql(
+                "DELETE FROM feed_channel WHERE rss_url IN (SELECT old_url FROM canonical_url);",
+            ),
+            Step::Sql(
+                "DELETE FROM feed_health WHERE rss_url IN (SELECT old_url FROM canonical_url);",
+            ),
+            Step::Sql(
+                "DELETE FROM delivered_tweet WHERE rss_url IN (SELECT old_url FROM canonical_url);",
+            ),
+            Step::Sql(
+                "DELETE FROM nitter_instance WHERE rss_url IN (SELECT old_url FROM canonical_url);",
+            ),
+            Step::Sql(
+                "DELETE FROM last_item WHERE rss_url IN (SELECT old_url FROM canonical_url);",
+            ),
+            Step::Sql("UPDATE last_item SET account = lower(account);"),
+            Step::Sql("DROP TABLE canonical_url;"),
+        ],
+    },
+    // Nitter 以外のフィード (RSS / Atom) も購読できるよう、取得元の種類を持たせる
+    Migration {
+        version: 3,
+        description: "add feed source kind",
+        steps: &[Step::AddColumn {
+            table: "last_item",
+            column: "source",
+            definition: "TEXT NOT NULL DEFAULT 'nitter'",
+        }],
+    },
+    // Mastodon のアカウント ID など、取得に必要な取得元での ID
+    Migration {
+        version: 4,
+        description: "add remote id of feed source",
+        steps: &[Step::AddColumn {
+            table: "last_item",
+            column: "remote_id",
+            definition: "TEXT NOT NULL DEFAULT ''",
+        }],
+    },
+];
+
+pub async fn run(pool: &SqlitePool) -> anyhow::Result<()> {
+    let _table = sqlx::query(SCHEMA_VERSION_TABLE).execute(pool).await?;
+
+    let current = sqlx::query_scalar::<_, i64>(
+        "
+    SELECT COALESCE(MAX(version), 0)
+    FROM schema_version
+    ",
+    )
+    .fetch_one(pool)
+    .await?;
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let mut tx = pool.begin().await?;
+        for step in migration.steps {
+            step.apply(&mut tx).await?;
+        }
+        let _query = sqlx::query(
+            "
+    INSERT INTO schema_version (version, description, applied_at)
+    VALUES ($1, $2, datetime('now', 'localtime'))
+    ",
+        )
+        .bind(migration.version)
+        .bind(migration.description)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        println!(
+            "migrated to schema version {}: {}",
+            migration.version, migration.description
+        );
+    }
+
+    Ok(())
+}
+
+impl Step {
+    async fn apply(&self, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+        match self {
+            Self::Sql(sql) => {
+                let _query = sqlx::query(sql).execute(&mut *conn).await?;
+            }
+            Self::AddColumn {
+                table,
+                column,
+                definition,
+            } => {
+                let i_exist = sqlx::query_scalar::<_, i32>(
+                    "
+    SELECT EXISTS
+    (
+        SELECT 1 FROM pragma_table_info($1) WHERE name = $2
+    ) AS exists_key
+    ",
+                )
+                .bind(table)
+                .bind(column)
+                .fetch_one(&mut *conn)
+                .await?;
+
+                if i_exist.eq(&0) {
+                    let _alter = sqlx::query(&format!(
+                        "ALTER TABLE {table} ADD COLUMN {column} {definition};"
+                    ))
+                    .execute(&mut *conn)
+                    .await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn upgrade_three_table_layout_test() {
+        // インメモリの DB は接続ごとに別になるため、接続を 1 つに限る
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for sql in [
+            "CREATE TABLE last_item (rss_url TEXT NOT NULL PRIMARY KEY, account TEXT NOT NULL, date TEXT NOT NULL DEFAULT '')",
+            "CREATE TABLE feed_channel (rss_url TEXT NOT NULL, channel TEXT NOT NULL, PRIMARY KEY (rss_url, channel))",
+            "CREATE TABLE nitter_instance (rss_url TEXT NOT NULL PRIMARY KEY, nitter TEXT NOT NULL)",
+            "INSERT INTO last_item (rss_url, account) VALUES ('https://nitter.net/test/rss', 'test')",
+            "INSERT INTO feed_channel (rss_url, channel) VALUES ('https://nitter.net/test/rss', 'C0000')",
+            "INSERT INTO nitter_instance (rss_url, nitter) VALUES ('https://nitter.net/test/rss', 'nitter.net')",
+            // 正規化すると上と同じになる購読
+            "INSERT INTO last_item (rss_url, account) VALUES ('https://nitter.net/Test/rss/', 'Test')",
+            "INSERT INTO feed_channel (rss_url, channel) VALUES ('https://nitter.net/Test/rss/', 'C0001')",
+            "INSERT INTO nitter_instance (rss_url, nitter) VALUES ('https://nitter.net/Test/rss/', 'nitter.net')",
+        ] {
+            sqlx::query(sql).execute(&pool).await.unwrap();
+        }
+
+        run(&pool).await.unwrap();
+        // 2 回目以降は何もしない
+        run(&pool).await.unwrap();
+
+        let (channel, include_retweets, interval_minutes) =
+            sqlx::query_as::<_, (String, bool, i64)>(
+                "SELECT channel, include_retweets, interval_minutes FROM feed_channel WHERE rss_url = 'https://nitter.net/test/rss'",
+            )
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            ("C0000", true, 5),
+            (channel.as_str(), include_retweets, interval_minutes)
+        );
+
+        let versions = sqlx::query_scalar::<_, i64>("SELECT version FROM schema_version")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(vec![1, 2, 3, 4], versions);
+
+        let channels = sqlx::query_as::<_, (String, String)>(
+            "SELECT rss_url, channel FROM feed_channel ORDER BY channel",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            vec![
+                (
+                    "https://nitter.net/test/rss".to_string(),
+                    "C0000".to_string()
+                ),
+                (
+                    "https://nitter.net/test/rss".to_string(),
+                    "C0001".to_string()
+                ),
+            ],
+            channels
+        );
+        let accounts = sqlx::query_scalar::<_, String>("SELECT account FROM last_item")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(vec!["test".to_string()], accounts);
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use dotenvy::dotenv;
+use tokio::time::Duration;
+use url::Url;
+
+use crate::utils;
+
+// 連続でこの回数取得に失敗したアカウントは別インスタンスに移す
+const FAILOVER_THRESHOLD: u32 = 3;
+// 不調と判定したインスタンスを再び候補に戻すまでの時間
+const INSTANCE_COOLDOWN_MINUTES: u64 = 30;
+
+#[derive(Debug, Default)]
+struct InstanceHealth {
+    failures: u32,
+    unhealthy_since: Option<Instant>,
+}
+
+impl InstanceHealth {
+    fn is_healthy(&self) -> bool {
+        self.unhealthy_since.is_none_or(|since| {
+            since.elapsed() >= Duration::from_secs(INSTANCE_COOLDOWN_MINUTES * 60)
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    instances: HashMap<String, InstanceHealth>,
+    feed_failures: HashMap<String, u32>,
+}
+
+// 切り替え先となる Nitter インスタンスの一覧と、その稼働状況
+#[derive(Debug, Clone, Default)]
+pub struct NitterPool {
+    nitters: Arc<Vec<String>>,
+    state: Arc<Mutex<PoolState>>,
+}
+
+impl NitterPool {
+    pub fn new(nitters: Vec<String>) -> Self {
+        Self {
+            nitters: Arc::new(nitters),
+            state: Arc::default(),
+        }
+    }
+
+    // NITTER_POOL (カンマ区切り) が無ければ DEFAULT_NITTER_URL のみを使う
+    pub fn from_env() -> anyhow::Result<Self> {
+        dotenv().ok();
+        let pool_str = env::var("NITTER_POOL").or_else(|_| env::var("DEFAULT_NITTER_URL"))?;
+
+        let nitters = pool_str
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                let url = Url::parse(s)?;
+                let nitter = utils::nitter_url_to_nitter(&url)?.to_string();
+                anyhow::Ok(nitter)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self::new(nitters))
+    }
+
+    pub fn record_success(&self, nitter: &str, rss_url: &Url) {
+        let mut state = self.state.lock().unwrap();
+        state.feed_failures.remove(rss_url.as_str());
+        state
+            .instances
+            .insert(nitter.to_string(), InstanceHealth::default());
+    }
+
+    // 接続できない、5xx を返すなどインスタンス自体の不調のみを数える
+    pub fn record_instance_failure(&self, nitter: &str) {
+        let mut state = self.state.lock().unwrap();
+
+        let health = state.instances.entry(nitter.to_string()).or_default();
+        health.failures += 1;
+        if health.failures >= FAILOVER_THRESHOLD && health.unhealthy_since.is_none() {
+            health.unhealthy_since = Some(Instant::now());
+        }
+    }
+
+    // 切り替えが必要であれば true を返す
+    pub fn record_feed_failure(&self, rss_url: &Url) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        let feed_failures = state
+            .feed_failures
+            .entry(rss_url.as_str().to_string())
+            .or_default();
+        *feed_failures += 1;
+
+        *feed_failures >= FAILOVER_THRESHOLD
+    }
+
+    pub fn reset_feed(&self, rss_url: &Url) {
+        self.state
+            .lock()
+            .unwrap()
+            .feed_failures
+            .remove(rss_url.as_str());
+    }
+
+    // 現在のインスタンス以外で、失敗の少ないものを選ぶ
+    pub fn healthy_nitter(&self, current: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+
+        self.nitters
+            .iter()
+            .filter(|nitter| nitter.as_str() != current)
+            .filter_map(|nitter| match state.instances.get(nitter) {
+                Some(health) if !health.is_healthy() => None,
+                Some(health) => Some((health.failures, nitter)),
+                None => Some((0, nitter)),
+            })
+            .min_by_key(|(failures, _)| *failures)
+            .map(|(_, nitter)| nitter.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn healthy_nitter_test() {
+        let pool = NitterPool::new(vec![
+            "nitter.net".to_string(),
+            "nitter.it".to_string(),
+            "nitter.cz".to_string(),
+        ]);
+        let rss_url = Url::parse("https://nitter.net/test/rss").unwrap();
+
+        for _ in 0..FAILOVER_THRESHOLD {
+            pool.record_instance_failure("nitter.it");
+        }
+        // アカウント単位の失敗はインスタンスの不調として数えない
+        for _ in 1..FAILOVER_THRESHOLD {
+            assert!(!pool.record_feed_failure(&rss_url));
+        }
+        assert!(pool.record_feed_failure(&rss_url));
+
+        assert_eq!(
+            Some("nitter.cz".to_string()),
+            pool.healthy_nitter("nitter.net")
+        );
+        assert_eq!(
+            Some("nitter.net".to_string()),
+            pool.healthy_nitter("nitter.cz")
+        );
+    }
+}
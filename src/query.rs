@@ -1,17 +1,26 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, env, str::FromStr, time::Duration};
 
+use dotenvy::dotenv;
 use slack_morphism::SlackChannelId;
-use sqlx::{migrate::MigrateDatabase, FromRow, Sqlite, SqlitePool};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+    FromRow, QueryBuilder, Sqlite, SqlitePool,
+};
 use url::Url;
 
-use crate::utils;
+use crate::{
+    feed_source::SourceKind,
+    filter::{FilterKind, SubscriptionOptions},
+    migration, utils,
+};
 
-const DB_URL: &str = "last-items.db";
-
-#[derive(Debug, FromRow)]
-pub struct LastDate {
-    date: String,
-}
+const DEFAULT_DB_URL: &str = "last-items.db";
+// 書き込みが重なった場合に、ロックの解放を待つ時間
+const BUSY_TIMEOUT_SECONDS: u64 = 5;
+// フィードから消えてからこの日数が経った配信済みツイートは削除する
+const DELIVERED_RETENTION_DAYS: i64 = 30;
+// 取得間隔の上限 (1 日)、これより長いと時刻の計算が溢れうる
+pub const MAX_INTERVAL_MINUTES: i64 = 1440;
 
 #[derive(Debug, FromRow)]
 pub struct FeedChannel {
@@ -22,225 +31,873 @@ pub struct RSSUrl {
     rss_url: String,
 }
 #[derive(Debug, FromRow)]
+pub struct Feed {
+    rss_url: String,
+    interval_minutes: i64,
+}
+// 条件付きリクエストに使う、前回のレスポンスのヘッダ
+#[derive(Debug, Default, FromRow)]
+pub struct FeedCache {
+    pub etag: String,
+    pub last_modified: String,
+}
+#[derive(Debug, FromRow)]
 pub struct Nitter {
     nitter: String,
 }
-
-pub async fn setup_db() -> anyhow::Result<()> {
-    if !Sqlite::database_exists(DB_URL).await? {
-        Sqlite::create_database(DB_URL).await?;
+#[derive(Debug, FromRow)]
+pub struct DeliveredTweet {
+    status_id: String,
+}
+#[derive(Debug, FromRow)]
+pub struct Filter {
+    kind: String,
+    pattern: String,
+}
+#[derive(Debug, FromRow)]
+pub struct ChannelSetting {
+    pub layout: String,
+    pub images: String,
+}
+impl Default for ChannelSetting {
+    fn default() -> Self {
+        Self {
+            layout: "link".to_string(),
+            images: "blocks".to_string(),
+        }
     }
+}
+#[derive(Debug, FromRow)]
+pub struct CircuitEvent {
+    pub nitter: String,
+    pub state: String,
+    pub reason: String,
+    pub changed_at: String,
+}
+#[derive(Debug, FromRow)]
+pub struct FeedHealth {
+    pub failures: i64,
+    pub failing_minutes: i64,
+    pub alerted: bool,
+}
+#[derive(Debug, FromRow)]
+pub struct Subscription {
+    pub rss_url: String,
+    pub account: String,
+    pub nitter: String,
+    pub date: String,
+    pub added_at: String,
+    pub source: String,
+}
 
-    let pool = SqlitePool::connect(DB_URL).await?;
-    let _last_item = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS last_item
-(
-    rss_url TEXT NOT NULL PRIMARY KEY,
-    account TEXT NOT NULL,
-    date TEXT NOT NULL DEFAULT ''
-);",
+// 新しく購読するフィードの、last_item に記録する情報
+#[derive(Debug, Clone, Copy)]
+pub struct NewFeed<'a> {
+    pub account: &'a str,
+    pub source: SourceKind,
+    // 取得元での ID (Mastodon のアカウント ID など、不要なら空)
+    pub remote_id: &'a str,
+}
+
+// DATABASE_URL が無ければ作業ディレクトリの last-items.db を使う
+pub fn db_url_from_env() -> String {
+    dotenv().ok();
+    env::var("DATABASE_URL").unwrap_or_else(|_| DEFAULT_DB_URL.to_string())
+}
+
+// 以降のクエリはすべてここで作成したプールを共有する
+// expected input: last-items.db, sqlite://path/to/db, sqlite::memory:
+pub async fn setup_db(db_url: &str) -> anyhow::Result<SqlitePool> {
+    let options = SqliteConnectOptions::from_str(db_url)?
+        .create_if_missing(true)
+        .busy_timeout(Duration::from_secs(BUSY_TIMEOUT_SECONDS))
+        .foreign_keys(true);
+
+    let pool = if db_url.contains(":memory:") {
+        // インメモリの DB は接続ごとに別になるため、1 つの接続を閉じずに使い続ける
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(options)
+            .await?
+    } else {
+        SqlitePoolOptions::new()
+            .connect_with(options.journal_mode(SqliteJournalMode::Wal))
+            .await?
+    };
+
+    migration::run(&pool).await?;
+
+    Ok(pool)
+}
+
+// インスタンスから取得するフィードと、その取得間隔
+// 取得間隔はチャンネルごとに設定され、最も短いものに合わせて取得する
+pub async fn fetch_feeds(pool: &SqlitePool, nitter: &str) -> anyhow::Result<Vec<(Url, Duration)>> {
+    // 追跡しているチャンネルが存在しない場合は選ばない
+    let feeds = sqlx::query_as::<_, Feed>(
+        "
+        SELECT fc.rss_url, MIN(fc.interval_minutes) AS interval_minutes
+        FROM feed_channel fc
+        INNER JOIN nitter_instance ni ON fc.rss_url = ni.rss_url
+        WHERE ni.nitter = $1
+        GROUP BY fc.rss_url
+    ",
+    )
+    .bind(nitter)
+    .fetch_all(pool)
+    .await?
+    .iter()
+    .map(|f| {
+        let url = Url::parse(&f.rss_url)?;
+        let minutes = u64::try_from(f.interval_minutes.clamp(1, MAX_INTERVAL_MINUTES))?;
+        anyhow::Ok((url, Duration::from_secs(minutes * 60)))
+    })
+    .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(feeds)
+}
+pub async fn fetch_nitters(pool: &SqlitePool) -> anyhow::Result<HashSet<String>> {
+    // 追跡しているチャンネルが存在しない場合は選ばない
+    let nitter = sqlx::query_as::<_, Nitter>(
+        "
+        SELECT ni.nitter
+        FROM nitter_instance ni
+        INNER JOIN feed_channel fc ON fc.rss_url = ni.rss_url
+    ",
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|n| n.nitter)
+    .collect::<HashSet<_>>();
+
+    Ok(nitter)
+}
+pub async fn fetch_channels(
+    pool: &SqlitePool,
+    rss_url: &Url,
+) -> anyhow::Result<Vec<SlackChannelId>> {
+    let channels = sqlx::query_as::<_, FeedChannel>(
+        "
+    SELECT channel
+    FROM feed_channel
+    WHERE rss_url = $1
+    ",
+    )
+    .bind(rss_url.as_str())
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| SlackChannelId::new(r.channel))
+    .collect::<Vec<_>>();
+
+    Ok(channels)
+}
+pub async fn count_subscriptions(
+    pool: &SqlitePool,
+    channel: &SlackChannelId,
+) -> anyhow::Result<i64> {
+    let count = sqlx::query_scalar::<_, i64>(
+        "
+    SELECT COUNT(*)
+    FROM feed_channel
+    WHERE channel = $1
+    ",
+    )
+    .bind(channel.to_string())
+    .fetch_one(pool)
+    .await?;
+
+    Ok(count)
+}
+pub async fn fetch_subscriptions(
+    pool: &SqlitePool,
+    channel: &SlackChannelId,
+    limit: i64,
+    offset: i64,
+) -> anyhow::Result<Vec<Subscription>> {
+    let subscriptions = sqlx::query_as::<_, Subscription>(
+        "
+    SELECT fc.rss_url, li.account, ni.nitter, li.date, fc.added_at, li.source
+    FROM feed_channel fc
+    INNER JOIN last_item li ON fc.rss_url = li.rss_url
+    INNER JOIN nitter_instance ni ON fc.rss_url = ni.rss_url
+    WHERE fc.channel = $1
+    ORDER BY li.account
+    LIMIT $2 OFFSET $3
+    ",
     )
-    .execute(&pool)
+    .bind(channel.to_string())
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
     .await?;
 
-    let _feed_channel = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS feed_channel
-(
-    rss_url TEXT NOT NULL,
-    channel TEXT NOT NULL,
-    PRIMARY KEY (rss_url, channel)
-);",
+    Ok(subscriptions)
+}
+
+// last_item / nitter_instance が無ければ作成し、チャンネルとの紐付けを 1 つのトランザクションで行う
+// 新しく紐付けた場合のみ、取得元のインスタンス (RSS / Atom フィードはそのホスト) を返す
+pub async fn insert_subscription(
+    pool: &SqlitePool,
+    channel: &SlackChannelId,
+    url: &Url,
+    feed: NewFeed<'_>,
+    options: SubscriptionOptions,
+) -> anyhow::Result<Option<String>> {
+    let nitter = utils::nitter_url_to_nitter(url)?;
+    let mut tx = pool.begin().await?;
+
+    let _last_item = sqlx::query(
+        "
+    INSERT INTO last_item (rss_url, account, source, remote_id)
+    VALUES ($1, $2, $3, $4)
+    ON CONFLICT (rss_url) DO NOTHING
+    ",
     )
-    .execute(&pool)
+    .bind(url.as_str())
+    .bind(feed.account)
+    .bind(feed.source.as_str())
+    .bind(feed.remote_id)
+    .execute(&mut tx)
     .await?;
 
+    // 切り替え済みのインスタンスがあればそのまま使う
     let _nitter_instance = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS nitter_instance
-(
-    rss_url TEXT NOT NULL PRIMARY KEY,
-    nitter TEXT NOT NULL,
-    FOREIGN KEY (rss_url) REFERENCES last_item(rss_url) ON DELETE CASCADE
-);",
+        "
+    INSERT INTO nitter_instance (rss_url, nitter)
+    VALUES ($1, $2)
+    ON CONFLICT (rss_url) DO NOTHING
+    ",
+    )
+    .bind(url.as_str())
+    .bind(nitter)
+    .execute(&mut tx)
+    .await?;
+
+    let feed_channel = sqlx::query(
+        "
+    INSERT INTO feed_channel
+        (rss_url, channel, added_at, include_retweets, include_replies, include_quotes)
+    VALUES ($1, $2, datetime('now', 'localtime'), $3, $4, $5)
+    ON CONFLICT (rss_url, channel) DO NOTHING
+    ",
+    )
+    .bind(url.as_str())
+    .bind(channel.to_string())
+    .bind(options.include_retweets)
+    .bind(options.include_replies)
+    .bind(options.include_quotes)
+    .execute(&mut tx)
+    .await?;
+
+    if feed_channel.rows_affected() == 0 {
+        tx.rollback().await?;
+        return Ok(None);
+    }
+
+    let current_nitter = sqlx::query_as::<_, Nitter>(
+        "
+    SELECT nitter
+    FROM nitter_instance
+    WHERE rss_url = $1
+    ",
+    )
+    .bind(url.as_str())
+    .fetch_one(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(Some(current_nitter.nitter))
+}
+
+// このチャンネルとの紐付けのみを削除し、削除した件数を返す
+pub async fn remove_subscriptions(
+    pool: &SqlitePool,
+    channel: &SlackChannelId,
+    rss_urls: &[&str],
+) -> anyhow::Result<u64> {
+    let mut tx = pool.begin().await?;
+    let mut removed = 0;
+
+    for rss_url in rss_urls {
+        let query = sqlx::query(
+            "
+    DELETE
+    FROM feed_channel
+    WHERE rss_url = $1 AND channel = $2
+    ",
+        )
+        .bind(rss_url)
+        .bind(channel.to_string())
+        .execute(&mut tx)
+        .await?;
+        removed += query.rows_affected();
+    }
+    tx.commit().await?;
+
+    Ok(removed)
+}
+
+// フィードの取得元と、取得元での ID
+pub async fn fetch_feed_source(
+    pool: &SqlitePool,
+    rss_url: &Url,
+) -> anyhow::Result<(SourceKind, String)> {
+    let (source, remote_id) = sqlx::query_as::<_, (String, String)>(
+        "
+    SELECT source, remote_id
+    FROM last_item
+    WHERE rss_url = $1
+    ",
+    )
+    .bind(rss_url.as_str())
+    .fetch_one(pool)
+    .await?;
+
+    Ok((source.parse()?, remote_id))
+}
+
+pub async fn update_last_date(pool: &SqlitePool, rss_url: &Url, date: &str) -> anyhow::Result<()> {
+    let _query = sqlx::query(
+        "
+    UPDATE  last_item
+    SET date = $1
+    WHERE rss_url = $2
+    ",
     )
-    .execute(&pool)
+    .bind(date)
+    .bind(rss_url.as_str())
+    .execute(pool)
     .await?;
 
     Ok(())
 }
 
-pub async fn nitter_exist(nitter: &str) -> anyhow::Result<bool> {
-    let pool = SqlitePool::connect(DB_URL).await?;
-    let i_exist = sqlx::query_scalar::<_, i32>(
+// 購読中のフィードの現在の取得元 (切り替え後のインスタンス)
+pub async fn fetch_nitter(pool: &SqlitePool, rss_url: &Url) -> anyhow::Result<Option<String>> {
+    let nitter = sqlx::query_scalar::<_, String>(
         "
-    SELECT EXISTS
-    (
-        SELECT 1 FROM nitter_instance WHERE nitter = $1
-    ) AS exists_key
+    SELECT nitter
+    FROM nitter_instance
+    WHERE rss_url = $1
+    ",
+    )
+    .bind(rss_url.as_str())
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(nitter)
+}
+
+pub async fn update_nitter(pool: &SqlitePool, rss_url: &Url, nitter: &str) -> anyhow::Result<()> {
+    let _query = sqlx::query(
+        "
+    UPDATE nitter_instance
+    SET previous_nitter = nitter, nitter = $1
+    WHERE rss_url = $2
     ",
     )
     .bind(nitter)
-    .fetch_one(&pool)
+    .bind(rss_url.as_str())
+    .execute(pool)
     .await?;
 
-    let exist = i_exist.eq(&1);
+    Ok(())
+}
 
-    Ok(exist)
+pub async fn fetch_delivered_ids(
+    pool: &SqlitePool,
+    rss_url: &Url,
+) -> anyhow::Result<HashSet<String>> {
+    let status_ids = sqlx::query_as::<_, DeliveredTweet>(
+        "
+    SELECT status_id
+    FROM delivered_tweet
+    WHERE rss_url = $1
+    ",
+    )
+    .bind(rss_url.as_str())
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|d| d.status_id)
+    .collect::<HashSet<_>>();
+
+    Ok(status_ids)
 }
 
-pub async fn fetch_last_date(rss_url: &Url) -> anyhow::Result<String> {
-    let pool = SqlitePool::connect(DB_URL).await?;
+// フィードに含まれているツイートを配信済みとして記録し、保持期間を過ぎたものを削除する
+pub async fn upsert_delivered_ids(
+    pool: &SqlitePool,
+    rss_url: &Url,
+    status_ids: &[String],
+) -> anyhow::Result<()> {
+    if !status_ids.is_empty() {
+        let mut query_builder = QueryBuilder::<Sqlite>::new(
+            "INSERT INTO delivered_tweet (rss_url, status_id, last_seen) ",
+        );
+        query_builder.push_values(status_ids, |mut b, status_id| {
+            b.push_bind(rss_url.as_str())
+                .push_bind(status_id)
+                .push("datetime('now')");
+        });
+        query_builder
+            .push(" ON CONFLICT (rss_url, status_id) DO UPDATE SET last_seen = excluded.last_seen");
+        let _query = query_builder.build().execute(pool).await?;
+    }
 
-    let last_date = sqlx::query_as::<_, LastDate>(
+    let _prune = sqlx::query(
         "
-    SELECT date
-    FROM last_item
-    WHERE rss_url = $1
+    DELETE
+    FROM delivered_tweet
+    WHERE rss_url = $1 AND last_seen < datetime('now', $2)
     ",
     )
     .bind(rss_url.as_str())
-    .fetch_one(&pool)
+    .bind(format!("-{DELIVERED_RETENTION_DAYS} days"))
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn fetch_channel_setting(
+    pool: &SqlitePool,
+    channel: &SlackChannelId,
+) -> anyhow::Result<ChannelSetting> {
+    let setting = sqlx::query_as::<_, ChannelSetting>(
+        "
+    SELECT layout, images
+    FROM channel_setting
+    WHERE channel = $1
+    ",
+    )
+    .bind(channel.to_string())
+    .fetch_optional(pool)
     .await?
-    .date;
+    .unwrap_or_default();
 
-    Ok(last_date)
+    Ok(setting)
 }
-pub async fn fetch_rss_urls(nitter: &str) -> anyhow::Result<Vec<Url>> {
-    let pool = SqlitePool::connect(DB_URL).await?;
 
-    // 追跡しているチャンネルが存在しない場合は選ばない
-    let rss_urls = sqlx::query_as::<_, RSSUrl>(
+pub async fn update_layout(
+    pool: &SqlitePool,
+    channel: &SlackChannelId,
+    layout: &str,
+) -> anyhow::Result<()> {
+    let _query = sqlx::query(
         "
-        SELECT fc.rss_url
-        FROM feed_channel fc
-        INNER JOIN nitter_instance ni ON fc.rss_url = ni.rss_url
-        WHERE ni.nitter = $1
+    INSERT INTO channel_setting (channel, layout)
+    VALUES ($1, $2)
+    ON CONFLICT (channel) DO UPDATE SET layout = excluded.layout
     ",
     )
-    .bind(nitter)
-    .fetch_all(&pool)
+    .bind(channel.to_string())
+    .bind(layout)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn update_images(
+    pool: &SqlitePool,
+    channel: &SlackChannelId,
+    images: &str,
+) -> anyhow::Result<()> {
+    let _query = sqlx::query(
+        "
+    INSERT INTO channel_setting (channel, images)
+    VALUES ($1, $2)
+    ON CONFLICT (channel) DO UPDATE SET images = excluded.images
+    ",
+    )
+    .bind(channel.to_string())
+    .bind(images)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+// チャンネルで購読しているアカウントの RSS URL
+pub async fn fetch_channel_rss_url(
+    pool: &SqlitePool,
+    channel: &SlackChannelId,
+    account: &str,
+) -> anyhow::Result<Option<Url>> {
+    let rss_url = sqlx::query_as::<_, RSSUrl>(
+        "
+    SELECT fc.rss_url
+    FROM feed_channel fc
+    INNER JOIN last_item li ON fc.rss_url = li.rss_url
+    WHERE li.account = $1 AND fc.channel = $2
+    ",
+    )
+    .bind(account)
+    .bind(channel.to_string())
+    .fetch_optional(pool)
     .await?
-    .iter()
-    .map(|s| {
-        let url = Url::parse(&s.rss_url)?;
-        anyhow::Ok(url)
-    })
-    .collect::<anyhow::Result<Vec<_>>>()?;
+    .map(|r| Url::parse(&r.rss_url))
+    .transpose()?;
 
-    Ok(rss_urls)
+    Ok(rss_url)
 }
-pub async fn fetch_nitters() -> anyhow::Result<HashSet<String>> {
-    let pool = SqlitePool::connect(DB_URL).await?;
 
-    // 追跡しているチャンネルが存在しない場合は選ばない
-    let nitter = sqlx::query_as::<_, Nitter>(
+pub async fn fetch_filters(
+    pool: &SqlitePool,
+    rss_url: &Url,
+    channel: &SlackChannelId,
+) -> anyhow::Result<Vec<(FilterKind, String)>> {
+    let filters = sqlx::query_as::<_, Filter>(
         "
-        SELECT ni.nitter
-        FROM nitter_instance ni
-        INNER JOIN feed_channel fc ON fc.rss_url = ni.rss_url
+    SELECT kind, pattern
+    FROM tweet_filter
+    WHERE rss_url = $1 AND channel = $2
     ",
     )
-    .fetch_all(&pool)
+    .bind(rss_url.as_str())
+    .bind(channel.to_string())
+    .fetch_all(pool)
     .await?
     .into_iter()
-    .map(|n| n.nitter)
-    .collect::<HashSet<_>>();
+    .map(|f| {
+        let kind = f.kind.parse::<FilterKind>()?;
+        anyhow::Ok((kind, f.pattern))
+    })
+    .collect::<anyhow::Result<Vec<_>>>()?;
 
-    Ok(nitter)
+    Ok(filters)
 }
-pub async fn fetch_channels(rss_url: &Url) -> anyhow::Result<Vec<SlackChannelId>> {
-    let pool = SqlitePool::connect(DB_URL).await?;
 
-    let channels = sqlx::query_as::<_, FeedChannel>(
+pub async fn insert_filter(
+    pool: &SqlitePool,
+    rss_url: &Url,
+    channel: &SlackChannelId,
+    kind: FilterKind,
+    pattern: &str,
+) -> anyhow::Result<()> {
+    let _query = sqlx::query(
         "
-    SELECT channel
+    INSERT OR IGNORE INTO tweet_filter (rss_url, channel, kind, pattern)
+    VALUES ($1, $2, $3, $4)
+    ",
+    )
+    .bind(rss_url.as_str())
+    .bind(channel.to_string())
+    .bind(kind.as_str())
+    .bind(pattern)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn remove_filters(
+    pool: &SqlitePool,
+    rss_url: &Url,
+    channel: &SlackChannelId,
+) -> anyhow::Result<()> {
+    let _query = sqlx::query(
+        "
+    DELETE
+    FROM tweet_filter
+    WHERE rss_url = $1 AND channel = $2
+    ",
+    )
+    .bind(rss_url.as_str())
+    .bind(channel.to_string())
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn feed_channel_exist(
+    pool: &SqlitePool,
+    channel: &SlackChannelId,
+    rss_url: &Url,
+) -> anyhow::Result<bool> {
+    let i_exist = sqlx::query_scalar::<_, i32>(
+        "
+    SELECT EXISTS
+    (
+        SELECT 1 FROM feed_channel WHERE rss_url = $1 AND channel = $2
+    ) AS exists_key
+    ",
+    )
+    .bind(rss_url.as_str())
+    .bind(channel.to_string())
+    .fetch_one(pool)
+    .await?;
+
+    let exist = i_exist.eq(&1);
+
+    Ok(exist)
+}
+
+pub async fn fetch_options(
+    pool: &SqlitePool,
+    rss_url: &Url,
+    channel: &SlackChannelId,
+) -> anyhow::Result<SubscriptionOptions> {
+    let options = sqlx::query_as::<_, SubscriptionOptions>(
+        "
+    SELECT include_retweets, include_replies, include_quotes
     FROM feed_channel
-    WHERE rss_url = $1
+    WHERE rss_url = $1 AND channel = $2
     ",
     )
     .bind(rss_url.as_str())
-    .fetch_all(&pool)
+    .bind(channel.to_string())
+    .fetch_optional(pool)
     .await?
-    .into_iter()
-    .map(|r| SlackChannelId::new(r.channel))
-    .collect::<Vec<_>>();
+    .unwrap_or_default();
 
-    Ok(channels)
+    Ok(options)
 }
-pub async fn insert_feed_channel(channel: &SlackChannelId, url: &Url) -> anyhow::Result<()> {
-    let pool = SqlitePool::connect(DB_URL).await?;
 
+pub async fn update_options(
+    pool: &SqlitePool,
+    rss_url: &Url,
+    channel: &SlackChannelId,
+    options: SubscriptionOptions,
+) -> anyhow::Result<()> {
     let _query = sqlx::query(
         "
-    INSERT INTO feed_channel  (rss_url, channel)
-    VALUES ($1, $2);
+    UPDATE feed_channel
+    SET include_retweets = $1, include_replies = $2, include_quotes = $3
+    WHERE rss_url = $4 AND channel = $5
     ",
     )
-    .bind(url.as_str())
+    .bind(options.include_retweets)
+    .bind(options.include_replies)
+    .bind(options.include_quotes)
+    .bind(rss_url.as_str())
     .bind(channel.to_string())
-    .execute(&pool)
+    .execute(pool)
     .await?;
 
     Ok(())
 }
 
-pub async fn insert_last_item(url: &Url) -> anyhow::Result<()> {
-    let pool = SqlitePool::connect(DB_URL).await?;
-    let account = utils::url_to_account(url)?;
-    let nitter = utils::nitter_url_to_nitter(url)?;
+pub async fn update_interval(
+    pool: &SqlitePool,
+    rss_url: &Url,
+    channel: &SlackChannelId,
+    interval_minutes: i64,
+) -> anyhow::Result<()> {
+    let _query = sqlx::query(
+        "
+    UPDATE feed_channel
+    SET interval_minutes = $1
+    WHERE rss_url = $2 AND channel = $3
+    ",
+    )
+    .bind(interval_minutes)
+    .bind(rss_url.as_str())
+    .bind(channel.to_string())
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn fetch_feed_cache(pool: &SqlitePool, rss_url: &Url) -> anyhow::Result<FeedCache> {
+    let cache = sqlx::query_as::<_, FeedCache>(
+        "
+    SELECT etag, last_modified
+    FROM last_item
+    WHERE rss_url = $1
+    ",
+    )
+    .bind(rss_url.as_str())
+    .fetch_optional(pool)
+    .await?
+    .unwrap_or_default();
 
+    Ok(cache)
+}
+
+pub async fn update_feed_cache(
+    pool: &SqlitePool,
+    rss_url: &Url,
+    cache: &FeedCache,
+) -> anyhow::Result<()> {
     let _query = sqlx::query(
         "
-    INSERT INTO last_item (rss_url, account)
-    VALUES ($1, $2);
-    INSERT INTO nitter_instance (rss_url, nitter)
-    VALUES ($3, $4)",
+    UPDATE last_item
+    SET etag = $1, last_modified = $2
+    WHERE rss_url = $3
+    ",
+    )
+    .bind(&cache.etag)
+    .bind(&cache.last_modified)
+    .bind(rss_url.as_str())
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+// サーキットブレーカーの状態の変化を記録する
+pub async fn insert_circuit_event(
+    pool: &SqlitePool,
+    nitter: &str,
+    state: &str,
+    reason: &str,
+) -> anyhow::Result<()> {
+    let _query = sqlx::query(
+        "
+    INSERT INTO circuit_event (nitter, state, reason, changed_at)
+    VALUES ($1, $2, $3, datetime('now', 'localtime'))
+    ",
     )
-    .bind(url.as_str())
-    .bind(account)
-    .bind(url.as_str())
     .bind(nitter)
-    .execute(&pool)
+    .bind(state)
+    .bind(reason)
+    .execute(pool)
     .await?;
 
     Ok(())
 }
 
-pub async fn remove_rss(channel: &SlackChannelId, account: &str) -> anyhow::Result<()> {
-    let pool = SqlitePool::connect(DB_URL).await?;
+// インスタンスごとの最新の状態
+pub async fn fetch_circuit_states(pool: &SqlitePool) -> anyhow::Result<Vec<CircuitEvent>> {
+    let events = sqlx::query_as::<_, CircuitEvent>(
+        "
+    SELECT nitter, state, reason, changed_at
+    FROM circuit_event
+    WHERE id IN (SELECT MAX(id) FROM circuit_event GROUP BY nitter)
+    ORDER BY nitter
+    ",
+    )
+    .fetch_all(pool)
+    .await?;
 
+    Ok(events)
+}
+
+// 連続失敗回数を増やし、失敗し続けている状況を返す
+pub async fn increment_feed_failures(
+    pool: &SqlitePool,
+    rss_url: &Url,
+    error: &str,
+) -> anyhow::Result<FeedHealth> {
     let _query = sqlx::query(
         "
-    DELETE 
-    FROM feed_channel
-    WHERE rss_url IN 
-        ( SELECT fc.rss_url
-        FROM feed_channel fc INNER JOIN last_item li
-        ON fc.rss_url = li.rss_url
-        WHERE li.account = $1 AND fc.channel = $2);
+    INSERT INTO feed_health (rss_url, failures, failing_since, last_error)
+    VALUES ($1, 1, datetime('now'), $2)
+    ON CONFLICT (rss_url) DO UPDATE
+    SET failures = failures + 1, last_error = excluded.last_error
     ",
     )
-    .bind(account)
-    .bind(channel.to_string())
-    .execute(&pool)
+    .bind(rss_url.as_str())
+    .bind(error)
+    .execute(pool)
+    .await?;
+
+    let health = sqlx::query_as::<_, FeedHealth>(
+        "
+    SELECT failures,
+        CAST((julianday('now') - julianday(failing_since)) * 24 * 60 AS INTEGER) AS failing_minutes,
+        alerted
+    FROM feed_health
+    WHERE rss_url = $1
+    ",
+    )
+    .bind(rss_url.as_str())
+    .fetch_one(pool)
+    .await?;
+
+    Ok(health)
+}
+
+pub async fn mark_feed_alerted(pool: &SqlitePool, rss_url: &Url) -> anyhow::Result<()> {
+    let _query = sqlx::query(
+        "
+    UPDATE feed_health
+    SET alerted = 1
+    WHERE rss_url = $1
+    ",
+    )
+    .bind(rss_url.as_str())
+    .execute(pool)
     .await?;
 
     Ok(())
 }
 
-pub async fn update_last_date(rss_url: &Url, date: &str) -> anyhow::Result<()> {
-    let pool = SqlitePool::connect(DB_URL).await?;
+// 失敗の記録が無ければ None、あれば通知済みかを返す
+pub async fn fetch_feed_alerted(pool: &SqlitePool, rss_url: &Url) -> anyhow::Result<Option<bool>> {
+    let alerted = sqlx::query_scalar::<_, bool>(
+        "
+    SELECT alerted
+    FROM feed_health
+    WHERE rss_url = $1
+    ",
+    )
+    .bind(rss_url.as_str())
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(alerted)
+}
 
+pub async fn delete_feed_health(pool: &SqlitePool, rss_url: &Url) -> anyhow::Result<()> {
     let _query = sqlx::query(
         "
-    UPDATE  last_item
-    SET date = $1
-    WHERE rss_url = $2
+    DELETE
+    FROM feed_health
+    WHERE rss_url = $1
     ",
     )
-    .bind(date)
     .bind(rss_url.as_str())
-    .execute(&pool)
+    .execute(pool)
     .await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn in_memory_db_test() {
+        let pool = setup_db("sqlite::memory:").await.unwrap();
+        let rss_url = Url::parse("https://nitter.net/test/rss").unwrap();
+        let channel = SlackChannelId::new("C0000".to_string());
+
+        let options = SubscriptionOptions::default();
+        let feed = NewFeed {
+            account: "test",
+            source: SourceKind::Nitter,
+            remote_id: "",
+        };
+        let nitter = insert_subscription(&pool, &channel, &rss_url, feed, options)
+            .await
+            .unwrap();
+        assert_eq!(Some("nitter.net".to_string()), nitter);
+        // 既に紐付いている場合は何も変えない
+        let nitter = insert_subscription(&pool, &channel, &rss_url, feed, options)
+            .await
+            .unwrap();
+        assert!(nitter.is_none());
+        let source = fetch_feed_source(&pool, &rss_url).await.unwrap();
+        assert_eq!((SourceKind::Nitter, String::new()), source);
+
+        let nitters = fetch_nitters(&pool).await.unwrap();
+        assert_eq!(HashSet::from(["nitter.net".to_string()]), nitters);
+
+        // last_item を消すと nitter_instance も消える
+        sqlx::query("DELETE FROM last_item")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(fetch_nitters(&pool).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,331 @@
+use std::str::FromStr;
+
+use regex::{Captures, Regex};
+use slack_morphism::prelude::*;
+use url::Url;
+
+use crate::{
+    feed_source::SourceKind,
+    fetch_rss::{Tweet, TwiInfo},
+    utils,
+};
+
+// Block Kit のセクションに載せられる文字数の上限
+const SECTION_TEXT_LIMIT: usize = 3000;
+// RSS / Atom の項目の要約は長くなりがちなため、この文字数で切り詰める
+const SUMMARY_LIMIT: usize = 300;
+
+// チャンネルごとのツイートの表示形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Layout {
+    // ツイートへのリンクのみを送り、展開は Slack に任せる
+    #[default]
+    Link,
+    // 本文や画像を Block Kit で組み立てて送る
+    Rich,
+}
+
+impl Layout {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Link => "link",
+            Self::Rich => "rich",
+        }
+    }
+}
+
+impl FromStr for Layout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "link" => Ok(Self::Link),
+            "rich" => Ok(Self::Rich),
+            _ => Err(anyhow::anyhow!("unknown layout: {s}")),
+        }
+    }
+}
+
+// 2 枚目以降の画像の送り方
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImageMode {
+    // 1 つのメッセージに画像をまとめる
+    #[default]
+    Blocks,
+    // ツイートのメッセージのスレッドに 1 枚ずつ返信する
+    Thread,
+}
+
+impl ImageMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Blocks => "blocks",
+            Self::Thread => "thread",
+        }
+    }
+}
+
+impl FromStr for ImageMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blocks" => Ok(Self::Blocks),
+            "thread" => Ok(Self::Thread),
+            _ => Err(anyhow::anyhow!("unknown image mode: {s}")),
+        }
+    }
+}
+
+pub fn rich_tweet_blocks(tweet: &Tweet, twi_info: &TwiInfo, pics: &[Url]) -> Vec<SlackBlock> {
+    if let Some(title) = &tweet.title {
+        return feed_item_blocks(tweet, twi_info, title, pics);
+    }
+
+    let TwiInfo {
+        icon_url,
+        display_name,
+        source,
+        profile_url,
+        ..
+    } = twi_info;
+
+    let mut blocks = Vec::new();
+
+    if tweet.is_retweet {
+        let verb = source.repost_verb();
+        let retweeted = profile_url.as_ref().map_or_else(
+            || format!("🔁 {} {verb}", utils::escape_mrkdwn(display_name)),
+            |profile| {
+                format!(
+                    "🔁 <{}|{}> {verb}",
+                    utils::escape_mrkdwn_url(profile),
+                    utils::escape_mrkdwn(display_name)
+                )
+            },
+        );
+        blocks.push(SlackContextBlock::new(vec![md!(retweeted)]).into());
+    }
+
+    // リツイートは購読しているアカウントではなく、元の投稿者の名前で表示する
+    let (author_icon, author_name) = if tweet.is_retweet {
+        (tweet.author_icon.as_ref(), tweet.author_name.as_deref())
+    } else {
+        (icon_url.as_ref(), Some(display_name.as_str()))
+    };
+    let author = utils::display_account(&tweet.author);
+    let author_text = author_name.map_or_else(
+        || author.clone(),
+        |name| format!("*{}* {author}", utils::escape_mrkdwn(name)),
+    );
+    blocks.push(author_block(
+        author_icon,
+        author_name.unwrap_or(&author),
+        &author_text,
+    ));
+
+    if let Some(warning) = &tweet.content_warning {
+        blocks.push(
+            SlackContextBlock::new(vec![md!("⚠️ CW: {}", utils::escape_mrkdwn(warning))]).into(),
+        );
+    }
+
+    if !tweet.text.is_empty() {
+        blocks.push(
+            SlackSectionBlock::new()
+                .with_text(md!(linked_text(tweet)))
+                .into(),
+        );
+    }
+
+    blocks.extend(image_blocks(pics));
+    blocks.extend(video_blocks(tweet));
+
+    // Twitter がカードとして表示するリンク
+    if let Some(card) = tweet.entities.links.last() {
+        blocks.push(
+            SlackContextBlock::new(vec![md!("🔗 <{}>", utils::escape_mrkdwn_url(card))]).into(),
+        );
+    }
+
+    if let Some(date) = tweet.pub_date.as_deref().and_then(slack_date) {
+        blocks.push(SlackContextBlock::new(vec![md!(date)]).into());
+    }
+
+    blocks.push(SlackActionsBlock::new(view_buttons(tweet, *source)).into());
+
+    blocks
+}
+
+// 本文中のメンションとハッシュタグを Twitter へのリンクにする
+fn linked_text(tweet: &Tweet) -> String {
+    let text = utils::escape_mrkdwn(&tweet.text);
+    let entities = &tweet.entities;
+    if entities.mentions.is_empty() && entities.hashtags.is_empty() {
+        return utils::truncate_mrkdwn(&text, SECTION_TEXT_LIMIT).to_string();
+    }
+
+    let re = Regex::new(r"([@#])(\w+)").unwrap();
+    let linked = re.replace_all(&text, |caps: &Captures| {
+        let name = &caps[2];
+        match &caps[1] {
+            "@" if entities
+                .mentions
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(name)) =>
+            {
+                format!("<https://twitter.com/{name}|@{name}>")
+            }
+            "#" if entities.hashtags.iter().any(|h| h == name) => {
+                format!("<https://twitter.com/hashtag/{name}|#{name}>")
+            }
+            _ => caps[0].to_string(),
+        }
+    });
+    // リンクを付けてから、リンクや文字参照の途中で切らないよう切り詰める
+    utils::truncate_mrkdwn(&linked, SECTION_TEXT_LIMIT).to_string()
+}
+
+fn view_buttons(tweet: &Tweet, source: SourceKind) -> Vec<SlackActionBlockElement> {
+    let button = |id: &str, label: &str, url: &Url| {
+        SlackBlockButtonElement::new(id.into(), pt!(label))
+            .with_url(url.clone())
+            .into()
+    };
+    match source {
+        SourceKind::Nitter => vec![
+            button("view_on_twitter", "View on Twitter", &tweet.twi_url),
+            button("view_on_nitter", "View on Nitter", &tweet.nitter_url),
+        ],
+        SourceKind::Mastodon => vec![button(
+            "view_on_mastodon",
+            "View on Mastodon",
+            &tweet.twi_url,
+        )],
+        SourceKind::Bluesky => vec![button("view_on_bluesky", "View on Bluesky", &tweet.twi_url)],
+        SourceKind::Rss => vec![button("open_link", "Open", &tweet.twi_url)],
+    }
+}
+
+// RSS / Atom の項目は、タイトルのリンクと要約を載せる
+pub fn feed_item_text(tweet: &Tweet, title: &str) -> String {
+    let link = format!(
+        "*<{}|{}>*",
+        utils::escape_mrkdwn_url(&tweet.twi_url),
+        utils::escape_mrkdwn(title)
+    );
+    if tweet.text.is_empty() {
+        return link;
+    }
+
+    let mut summary = tweet.text.chars().take(SUMMARY_LIMIT).collect::<String>();
+    if tweet.text.chars().count() > SUMMARY_LIMIT {
+        summary.push('…');
+    }
+    format!("{link}\n{}", utils::escape_mrkdwn(&summary))
+}
+
+fn feed_item_blocks(
+    tweet: &Tweet,
+    twi_info: &TwiInfo,
+    title: &str,
+    pics: &[Url],
+) -> Vec<SlackBlock> {
+    let TwiInfo {
+        icon_url,
+        display_name,
+        ..
+    } = twi_info;
+
+    let mut blocks = vec![
+        author_block(
+            icon_url.as_ref(),
+            display_name,
+            &format!("*{}*", utils::escape_mrkdwn(display_name)),
+        ),
+        SlackSectionBlock::new()
+            .with_text(md!(feed_item_text(tweet, title)))
+            .into(),
+    ];
+
+    blocks.extend(image_blocks(pics));
+
+    if let Some(date) = tweet.pub_date.as_deref().and_then(slack_date) {
+        blocks.push(SlackContextBlock::new(vec![md!(date)]).into());
+    }
+
+    blocks.push(SlackActionsBlock::new(view_buttons(tweet, twi_info.source)).into());
+
+    blocks
+}
+
+// 購読を開始したアカウントのアイコンと表示名を添える
+pub fn subscribed_blocks(twi_info: &TwiInfo, text: &str) -> Vec<SlackBlock> {
+    let TwiInfo {
+        icon_url,
+        display_name,
+        account,
+        ..
+    } = twi_info;
+
+    vec![
+        SlackSectionBlock::new().with_text(md!(text)).into(),
+        author_block(
+            icon_url.as_ref(),
+            display_name,
+            &format!(
+                "*{}* {}",
+                utils::escape_mrkdwn(display_name),
+                utils::display_account(account)
+            ),
+        ),
+    ]
+}
+
+// アイコンの無いフィードは名前のみを表示する
+fn author_block(icon_url: Option<&Url>, display_name: &str, text: &str) -> SlackBlock {
+    let mut elements = Vec::new();
+    if let Some(icon_url) = icon_url {
+        elements.push(
+            SlackBlockImageElement::new(icon_url.to_string(), display_name.to_string()).into(),
+        );
+    }
+    elements.push(md!(text));
+    SlackContextBlock::new(elements).into()
+}
+
+pub fn image_blocks(pics: &[Url]) -> Vec<SlackBlock> {
+    pics.iter()
+        .map(|pic| SlackImageBlock::new(pic.clone(), "image".to_string()).into())
+        .collect()
+}
+
+// 動画や GIF はサムネイルに ▶ の見出しを付け、再生できるリンクを添える
+pub fn video_blocks(tweet: &Tweet) -> Vec<SlackBlock> {
+    tweet
+        .videos
+        .iter()
+        .flat_map(|video| {
+            let label = if video.is_gif { "▶ GIF" } else { "▶ video" };
+            let link = video.url.as_ref().unwrap_or(&tweet.twi_url);
+            let thumb = video.thumb.as_ref().map(|thumb| {
+                SlackImageBlock::new(thumb.clone(), label.to_string())
+                    .with_title(pt!(label))
+                    .into()
+            });
+            let link =
+                SlackContextBlock::new(vec![md!("<{}|{}>", utils::escape_mrkdwn_url(link), label)])
+                    .into();
+            thumb.into_iter().chain([link])
+        })
+        .collect()
+}
+
+// 閲覧者のタイムゾーンで表示されるよう Slack の日付書式に変換する
+fn slack_date(pub_date: &str) -> Option<String> {
+    let date = chrono::DateTime::parse_from_rfc2822(pub_date).ok()?;
+    Some(format!(
+        "<!date^{}^{{date_short_pretty}} {{time}}|{pub_date}>",
+        date.timestamp()
+    ))
+}
@@ -0,0 +1,172 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+};
+
+use dotenvy::dotenv;
+use tokio::time::{Duration, Instant};
+use url::Url;
+
+const DEFAULT_MIN_SPACING_SECONDS: u64 = 30;
+const DEFAULT_REQUESTS_PER_HOUR: usize = 60;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_hours(1);
+
+// インスタンスごとのリクエスト間隔と上限
+#[derive(Debug, Clone, Copy)]
+pub struct InstanceLimits {
+    pub min_spacing: Duration,
+    pub requests_per_hour: usize,
+}
+
+impl Default for InstanceLimits {
+    fn default() -> Self {
+        Self {
+            min_spacing: Duration::from_secs(DEFAULT_MIN_SPACING_SECONDS),
+            requests_per_hour: DEFAULT_REQUESTS_PER_HOUR,
+        }
+    }
+}
+
+impl InstanceLimits {
+    pub fn from_env() -> Self {
+        dotenv().ok();
+        let default = Self::default();
+
+        let min_spacing = env::var("NITTER_MIN_SPACING_SECONDS")
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .map_or(default.min_spacing, Duration::from_secs);
+        let requests_per_hour = env::var("NITTER_REQUESTS_PER_HOUR")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(default.requests_per_hour);
+
+        Self {
+            min_spacing,
+            requests_per_hour,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FeedState {
+    interval: Duration,
+    next_due: Instant,
+}
+
+// 1 つのインスタンスに属するフィードそれぞれの次回取得時刻を管理する
+#[derive(Debug)]
+pub struct FeedSchedule {
+    limits: InstanceLimits,
+    feeds: HashMap<Url, FeedState>,
+    requests: VecDeque<Instant>,
+}
+
+impl FeedSchedule {
+    pub fn new(limits: InstanceLimits) -> Self {
+        Self {
+            limits,
+            feeds: HashMap::new(),
+            requests: VecDeque::new(),
+        }
+    }
+
+    // 新しく追加されたフィードはすぐに、購読が無くなったフィードは対象から外す
+    pub fn sync(&mut self, feeds: &[(Url, Duration)], now: Instant) {
+        self.feeds
+            .retain(|url, _| feeds.iter().any(|(feed_url, _)| feed_url == url));
+
+        for (url, interval) in feeds {
+            self.feeds
+                .entry(url.clone())
+                .and_modify(|state| {
+                    // 間隔を短くした場合は、新しい間隔で前倒しする
+                    if *interval < state.interval {
+                        state.next_due = state.next_due - state.interval + *interval;
+                    }
+                    state.interval = *interval;
+                })
+                .or_insert(FeedState {
+                    interval: *interval,
+                    next_due: now,
+                });
+        }
+    }
+
+    // 最も期限の早いフィードと、間隔・上限を守った上で取得してよい時刻
+    pub fn next(&self, now: Instant) -> Option<(Url, Instant)> {
+        let (url, state) = self.feeds.iter().min_by_key(|(_, state)| state.next_due)?;
+
+        let after_spacing = self
+            .requests
+            .back()
+            .map_or(now, |last| *last + self.limits.min_spacing);
+        let after_rate_limit = if self.requests.len() >= self.limits.requests_per_hour {
+            self.requests
+                .get(self.requests.len() - self.limits.requests_per_hour)
+                .map_or(now, |oldest| *oldest + RATE_LIMIT_WINDOW)
+        } else {
+            now
+        };
+
+        let at = state.next_due.max(after_spacing).max(after_rate_limit);
+        Some((url.clone(), at))
+    }
+
+    pub fn record(&mut self, url: &Url, now: Instant) {
+        if let Some(state) = self.feeds.get_mut(url) {
+            state.next_due = now + state.interval;
+        }
+
+        self.requests.push_back(now);
+        while self
+            .requests
+            .front()
+            .is_some_and(|oldest| now.duration_since(*oldest) >= RATE_LIMIT_WINDOW)
+        {
+            self.requests.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schedule_test() {
+        let limits = InstanceLimits {
+            min_spacing: Duration::from_secs(30),
+            requests_per_hour: 3,
+        };
+        let mut schedule = FeedSchedule::new(limits);
+        let now = Instant::now();
+        let five_minutes = Duration::from_mins(5);
+        let a = Url::parse("https://nitter.net/a/rss").unwrap();
+        let b = Url::parse("https://nitter.net/b/rss").unwrap();
+
+        schedule.sync(&[(a.clone(), five_minutes)], now);
+        let (url, at) = schedule.next(now).unwrap();
+        assert_eq!((&a, now), (&url, at));
+        schedule.record(&a, now);
+
+        // 後から追加されたフィードは、間隔を空けた上で先に取得される
+        schedule.sync(&[(a.clone(), five_minutes), (b.clone(), five_minutes)], now);
+        let (url, at) = schedule.next(now).unwrap();
+        assert_eq!((&b, now + limits.min_spacing), (&url, at));
+        schedule.record(&b, at);
+
+        let (url, at) = schedule.next(at).unwrap();
+        assert_eq!((&a, now + five_minutes), (&url, at));
+        schedule.record(&a, at);
+
+        // 1 時間あたりの上限に達した場合は、最も古いリクエストから 1 時間待つ
+        let (_, at) = schedule.next(at).unwrap();
+        assert_eq!(now + RATE_LIMIT_WINDOW, at);
+
+        // 購読が無くなったフィードは対象から外れる
+        schedule.sync(&[], now);
+        assert!(schedule.next(now).is_none());
+    }
+}
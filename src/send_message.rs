@@ -6,23 +6,40 @@ use slack_morphism::{
     prelude::{SlackApiChatPostMessageRequest, SlackHyperClient},
     SlackApiToken, SlackApiTokenType, SlackChannelId, SlackMessageContent,
 };
+use sqlx::SqlitePool;
+use url::Url;
 
 use crate::{
     fetch_rss::{Tweet, TwiInfo},
+    query,
+    render::{self, ImageMode, Layout},
     utils,
 };
 
 pub async fn send_to_channels(
-    channels: Vec<SlackChannelId>,
-    urls: &[Tweet],
+    db: &SqlitePool,
+    channel_tweets: Vec<(SlackChannelId, Vec<&Tweet>)>,
     client: Arc<SlackHyperClient>,
     twi_info: TwiInfo,
 ) -> anyhow::Result<()> {
-    let token = utils::get_token(&SlackApiTokenType::Bot)?;
-    let channel_stream = futures::stream::iter(channels);
+    let token = &utils::get_token(&SlackApiTokenType::Bot)?;
+    let client = &client;
+    let twi_info = &twi_info;
+    let channel_stream = futures::stream::iter(channel_tweets);
     channel_stream
-        .map(|channel| async {
-            send_tweets(channel, urls, Arc::clone(&client), &token, &twi_info).await
+        .map(|(channel, urls)| async move {
+            let setting = query::fetch_channel_setting(db, &channel).await?;
+            let layout = setting.layout.parse::<Layout>()?;
+            let image_mode = setting.images.parse::<ImageMode>()?;
+            send_tweets(
+                channel,
+                (layout, image_mode),
+                &urls,
+                Arc::clone(client),
+                token,
+                twi_info,
+            )
+            .await
         })
         .then(|s| s)
         .try_collect::<()>()
@@ -30,85 +47,215 @@ pub async fn send_to_channels(
     Ok(())
 }
 
+pub async fn send_notice(
+    channels: Vec<SlackChannelId>,
+    text: &str,
+    client: Arc<SlackHyperClient>,
+) -> anyhow::Result<()> {
+    let token = utils::get_token(&SlackApiTokenType::Bot)?;
+    let session = client.open_session(&token);
+
+    for channel in channels {
+        let content = SlackMessageContent::new().with_text(text.to_string());
+        let req = SlackApiChatPostMessageRequest::new(channel, content);
+        let _message_res = session
+            .chat_post_message(&req)
+            .await
+            .context("failed to post message.")?;
+    }
+    Ok(())
+}
+
+// 本文のメッセージと、2 枚目以降の画像を載せるメッセージ
+struct TweetMessages {
+    main: SlackMessageContent,
+    images: Vec<SlackMessageContent>,
+}
+
 async fn send_tweets(
     channel: SlackChannelId,
-    tweets: &[Tweet],
+    setting: (Layout, ImageMode),
+    tweets: &[&Tweet],
     client: Arc<SlackHyperClient>,
     token: &SlackApiToken,
     twi_info: &TwiInfo,
 ) -> anyhow::Result<()> {
+    let (layout, image_mode) = setting;
     let TwiInfo {
         display_name,
         icon_url,
-        account,
+        ..
     } = twi_info;
 
-    let tweets_contents = tweets
-        .iter()
-        .map(|tweet| {
-            let content_main_str = if utils::is_retweet(&tweet.twi_url, account) {
-                retweet_text(tweet, account, display_name).unwrap_or(tweet.twi_url.to_string())
-            } else {
-                tweet.twi_url.to_string()
-            };
-            let mut content_main = vec![SlackMessageContent::new().with_text(content_main_str)];
-            let mut imgs = tweet_imgs_contents(tweet);
-
-            content_main.append(&mut imgs);
-            content_main
-        })
-        .collect::<Vec<_>>();
-
-    let reqs = tweets_contents
-        .into_iter()
-        .flat_map(|contents| {
-            contents
-                .into_iter()
-                .map(|content| {
-                    SlackApiChatPostMessageRequest::new(channel.clone(), content)
-                        .with_username(display_name.to_string())
-                        .with_icon_url(icon_url.to_string())
-                })
-                .collect::<Vec<_>>()
-        })
-        .collect::<Vec<_>>();
-    let mut req_stream = futures::stream::iter(reqs);
+    let new_req = |content: SlackMessageContent, unfurl: bool| {
+        let req = SlackApiChatPostMessageRequest::new(channel.clone(), content)
+            .with_username(display_name.clone());
+        let req = match icon_url {
+            Some(icon_url) => req.with_icon_url(icon_url.to_string()),
+            None => req,
+        };
+        // 自前で組み立てた内容と Slack の展開が重複しないようにする
+        if unfurl {
+            req
+        } else {
+            req.with_unfurl_links(false).with_unfurl_media(false)
+        }
+    };
 
     let session = client.open_session(token);
 
-    while let Some(req) = req_stream.next().await {
-        let _message_res = session
-            .chat_post_message(&req)
+    for tweet in tweets {
+        let TweetMessages { main, images } = tweet_messages(tweet, twi_info, layout, image_mode);
+
+        let main_req = new_req(main, layout == Layout::Link);
+        let main_message = session
+            .chat_post_message(&main_req)
             .await
             .context("failed to post message.")?;
+
+        for image in images {
+            let image_req = match image_mode {
+                ImageMode::Blocks => new_req(image, false),
+                ImageMode::Thread => new_req(image, false).with_thread_ts(main_message.ts.clone()),
+            };
+            let _message_res = session
+                .chat_post_message(&image_req)
+                .await
+                .context("failed to post message.")?;
+        }
     }
     Ok(())
 }
 
-fn tweet_imgs_contents(tweet: &Tweet) -> Vec<SlackMessageContent> {
-    let pics = &tweet.pics;
-    match pics.len() {
-        0 | 1 => Vec::new(),
-        _ => pics
-            .iter()
-            .skip(1)
-            .map(|url| SlackMessageContent::new().with_text(url.to_string()))
-            .collect::<Vec<_>>(),
+fn tweet_messages(
+    tweet: &Tweet,
+    twi_info: &TwiInfo,
+    layout: Layout,
+    image_mode: ImageMode,
+) -> TweetMessages {
+    // 1 枚目の画像は本文のメッセージ (展開、または rich 表示) に含まれる
+    let rest_pics = tweet.pics.get(1..).unwrap_or_default();
+
+    match (layout, image_mode) {
+        // Slack の展開では動画が再生できないことがあるため、サムネイルを別に送る
+        (Layout::Link, ImageMode::Blocks) => TweetMessages {
+            main: link_tweet_content(tweet, twi_info),
+            images: images_content(rest_pics)
+                .into_iter()
+                .chain(videos_content(tweet))
+                .collect(),
+        },
+        (Layout::Link, ImageMode::Thread) => TweetMessages {
+            main: link_tweet_content(tweet, twi_info),
+            images: rest_pics
+                .iter()
+                .filter_map(|pic| images_content(std::slice::from_ref(pic)))
+                .chain(videos_content(tweet))
+                .collect(),
+        },
+        (Layout::Rich, ImageMode::Blocks) => TweetMessages {
+            main: rich_tweet_content(tweet, twi_info, &tweet.pics),
+            images: Vec::new(),
+        },
+        (Layout::Rich, ImageMode::Thread) => TweetMessages {
+            main: rich_tweet_content(tweet, twi_info, tweet.pics.get(..1).unwrap_or_default()),
+            images: rest_pics
+                .iter()
+                .filter_map(|pic| images_content(std::slice::from_ref(pic)))
+                .collect(),
+        },
     }
 }
 
+fn link_tweet_content(tweet: &Tweet, twi_info: &TwiInfo) -> SlackMessageContent {
+    let content_main_str = if let Some(title) = &tweet.title {
+        render::feed_item_text(tweet, title)
+    } else if tweet.is_retweet {
+        retweet_link_text(tweet, twi_info)
+    } else {
+        tweet.twi_url.to_string()
+    };
+    // 展開された本文より先に警告文が目に入るようにする
+    let content_main_str = match &tweet.content_warning {
+        Some(warning) => format!("⚠️ CW: {warning}\n{content_main_str}"),
+        None => content_main_str,
+    };
+    SlackMessageContent::new().with_text(content_main_str)
+}
+
+fn rich_tweet_content(tweet: &Tweet, twi_info: &TwiInfo, pics: &[Url]) -> SlackMessageContent {
+    // 通知などに使われる代替テキスト
+    let fallback = if let Some(title) = &tweet.title {
+        render::feed_item_text(tweet, title)
+    } else if tweet.is_retweet {
+        retweet_text(tweet, twi_info)
+    } else {
+        tweet.twi_url.to_string()
+    };
+
+    SlackMessageContent::new()
+        .with_text(fallback)
+        .with_blocks(render::rich_tweet_blocks(tweet, twi_info, pics))
+}
+
+fn images_content(pics: &[Url]) -> Option<SlackMessageContent> {
+    if pics.is_empty() {
+        return None;
+    }
+
+    let fallback = pics.iter().map(Url::as_str).collect::<Vec<_>>().join("\n");
+    let content = SlackMessageContent::new()
+        .with_text(fallback)
+        .with_blocks(render::image_blocks(pics));
+    Some(content)
+}
+
+fn videos_content(tweet: &Tweet) -> Option<SlackMessageContent> {
+    if tweet.videos.is_empty() {
+        return None;
+    }
+
+    let fallback = tweet
+        .videos
+        .iter()
+        .map(|video| video.url.as_ref().unwrap_or(&tweet.twi_url).as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let content = SlackMessageContent::new()
+        .with_text(fallback)
+        .with_blocks(render::video_blocks(tweet));
+    Some(content)
+}
+
 // retweet 時にプロフィールのリンクが展開されてしまう問題への姑息な対応
-// 解決次第コメントアウトを外す
-fn retweet_text(tweet: &Tweet, account: &str, display_name: &str) -> anyhow::Result<String> {
-    let _twi_profile_url = utils::account_to_twitter_profile(account)?;
-    let text = format!("{}\n{display_name} retweeted:", tweet.twi_url.as_str());
-    // let text = format!(
-    //     "{}\n<{}|{display_name}> retweeted:",
-    //     tweet.twi_url.as_str(),
-    //     twi_profile_url.as_str()
-    // );
-
-    Ok(text)
+// link 表示では Slack の展開に頼るため、プロフィールへのリンクを付けない
+fn retweet_link_text(tweet: &Tweet, twi_info: &TwiInfo) -> String {
+    format!(
+        "{}\n{} {}:",
+        tweet.twi_url.as_str(),
+        twi_info.display_name,
+        twi_info.source.repost_verb()
+    )
+}
+
+// rich 表示では展開を無効にして送るため、プロフィールへのリンクを付けられる
+fn retweet_text(tweet: &Tweet, twi_info: &TwiInfo) -> String {
+    let TwiInfo {
+        display_name,
+        source,
+        profile_url,
+        ..
+    } = twi_info;
+
+    let name = profile_url.as_ref().map_or_else(
+        || display_name.clone(),
+        |profile| format!("<{}|{display_name}>", utils::escape_mrkdwn_url(profile)),
+    );
+    format!(
+        "{}\n{name} {}:",
+        tweet.twi_url.as_str(),
+        source.repost_verb()
+    )
 }
 
 #[cfg(test)]
@@ -116,6 +263,7 @@ mod tests {
     use url::Url;
 
     use super::*;
+    use crate::{feed_source::SourceKind, fetch_rss::TweetEntities};
 
     #[test]
     fn retweet_text_test() {
@@ -123,12 +271,30 @@ mod tests {
         let twi_url = Url::parse(twi_url_str).unwrap();
         let tweet = Tweet {
             twi_url,
+            nitter_url: Url::parse("https://nitter.net/test/status/0000#m").unwrap(),
+            title: None,
+            author: "test".to_string(),
+            author_name: None,
+            author_icon: None,
+            content_warning: None,
+            text: String::default(),
+            pub_date: None,
             pics: Vec::default(),
+            videos: Vec::new(),
+            is_reply: false,
+            is_retweet: true,
+            quote_url: None,
+            entities: TweetEntities::default(),
+        };
+        let twi_info = TwiInfo {
+            icon_url: None,
+            display_name: "tester".to_string(),
+            account: "test".to_string(),
+            source: SourceKind::Nitter,
+            profile_url: utils::account_to_twitter_profile("test").ok(),
         };
-        let account = "test";
-        let display_name = "tester";
 
-        let rt_text = retweet_text(&tweet, account, display_name).unwrap();
+        let rt_text = retweet_text(&tweet, &twi_info);
 
         assert_eq!(
             "https://twitter.com/test/status/0000\n<https://twitter.com/test|tester> retweeted:",
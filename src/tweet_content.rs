@@ -0,0 +1,262 @@
+use scraper::{ElementRef, Html, Node, Selector};
+use url::Url;
+
+use crate::{
+    fetch_rss::{TweetEntities, Video},
+    utils,
+};
+
+// Nitter の RSS の description から取り出したツイートの内容
+#[derive(Debug, Default)]
+pub struct TweetContent {
+    // リンクを実際の URL に戻した本文
+    pub text: String,
+    pub pics: Vec<Url>,
+    pub videos: Vec<Video>,
+    pub quote_url: Option<Url>,
+    pub entities: TweetEntities,
+}
+
+// expected input: <p>text <a href="https://nitter.net/jack">@jack</a></p><img src="https://nitter.net/pic/media%2F...">
+pub fn parse(description: &str, nitter_url: &Url) -> TweetContent {
+    let fragment = Html::parse_fragment(description);
+    let mut parser = Parser {
+        nitter_host: nitter_url.host_str(),
+        own_id: utils::status_id(nitter_url.as_str()),
+        content: TweetContent::default(),
+    };
+    parser.walk(fragment.root_element());
+
+    let mut content = parser.content;
+    content.text = content.text.trim().to_string();
+    content
+}
+
+struct Parser<'a> {
+    nitter_host: Option<&'a str>,
+    own_id: Option<String>,
+    content: TweetContent,
+}
+
+impl Parser<'_> {
+    fn walk(&mut self, elem: ElementRef<'_>) {
+        for child in elem.children() {
+            match child.value() {
+                Node::Text(text) => self.content.text.push_str(text),
+                Node::Element(_) => {
+                    if let Some(child) = ElementRef::wrap(child) {
+                        self.element(child);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn element(&mut self, elem: ElementRef<'_>) {
+        match elem.value().name() {
+            "br" => self.content.text.push('\n'),
+            "p" => {
+                self.walk(elem);
+                self.content.text.push_str("\n\n");
+            }
+            "a" => self.link(elem),
+            "video" => self.video(elem),
+            "img" => {
+                if let Some(src) = elem.value().attr("src") {
+                    self.image(src);
+                }
+            }
+            _ => self.walk(elem),
+        }
+    }
+
+    // Nitter は外部リンクの表示を省略し、メンションなどを自身へのリンクにする
+    fn link(&mut self, elem: ElementRef<'_>) {
+        let label = elem.text().collect::<String>();
+        let href = elem.value().attr("href").and_then(|h| Url::parse(h).ok());
+
+        if let Some(name) = label.strip_prefix('@') {
+            self.content.entities.mentions.push(name.to_string());
+            self.content.text.push_str(&label);
+        } else if let Some(tag) = label.strip_prefix('#') {
+            self.content.entities.hashtags.push(tag.to_string());
+            self.content.text.push_str(&label);
+        } else if let Some(href) = href {
+            self.href(href);
+        } else {
+            self.content.text.push_str(&label);
+        }
+    }
+
+    // 外部へのリンクはそのまま、Nitter 内のリンクは Twitter の URL に戻す
+    fn href(&mut self, href: Url) {
+        if !self.is_internal(&href) {
+            self.content.text.push_str(href.as_str());
+            self.content.entities.links.push(href);
+            return;
+        }
+        let Ok(twi_url) = utils::nitter_url_to_twi(&href) else {
+            return;
+        };
+
+        // 自身とは別のツイートへのリンクを引用元とみなす
+        let status_id = utils::status_id(twi_url.as_str());
+        if self.content.quote_url.is_none() && status_id.is_some() && status_id != self.own_id {
+            self.content.quote_url = Some(twi_url.clone());
+        }
+        self.content.text.push_str(twi_url.as_str());
+    }
+
+    fn is_internal(&self, url: &Url) -> bool {
+        let host = url.host_str();
+        host == self.nitter_host || host.is_some_and(|host| matches!(host, "twitter.com" | "x.com"))
+    }
+
+    fn image(&mut self, src: &str) {
+        let Some(id) = image_id(src) else {
+            return;
+        };
+        let twi_url = twimg_url(&id);
+
+        // 動画を再生できない場合は、サムネイルのみが img で載る
+        if utils::is_video_thumb(&id) {
+            if !self.content.videos.iter().any(|v| v.thumb == twi_url) {
+                self.content.videos.push(Video {
+                    url: None,
+                    thumb: twi_url,
+                    is_gif: id.starts_with("tweet_video_thumb"),
+                });
+            }
+        } else if let Some(twi_url) = twi_url {
+            self.content.pics.push(twi_url);
+        }
+    }
+
+    // expected input: <video poster="..."><source src="..." type="video/mp4"></video>
+    fn video(&mut self, elem: ElementRef<'_>) {
+        let source = Selector::parse("source[src]").unwrap();
+        let url = elem
+            .value()
+            .attr("src")
+            .into_iter()
+            .chain(elem.select(&source).filter_map(|s| s.value().attr("src")))
+            .filter_map(|src| Url::parse(src).ok())
+            .find_map(|src| utils::get_video_url(&src));
+        let thumb = elem
+            .value()
+            .attr("poster")
+            .and_then(image_id)
+            .and_then(|id| twimg_url(&id));
+        // Nitter は GIF を class="gif" のループ再生の動画にする
+        let is_gif = elem.value().classes().any(|class| class == "gif")
+            || url
+                .as_ref()
+                .is_some_and(|url| url.path().starts_with("/tweet_video/"));
+
+        if url.is_some() || thumb.is_some() {
+            self.content.videos.push(Video { url, thumb, is_gif });
+        }
+    }
+}
+
+fn image_id(src: &str) -> Option<String> {
+    Url::parse(src)
+        .ok()
+        .and_then(|src| utils::get_image_id(&src))
+}
+
+fn twimg_url(id: &str) -> Option<Url> {
+    Url::parse("https://pbs.twimg.com/")
+        .and_then(|pic| pic.join(id))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_video_test() {
+        let description = concat!(
+            r#"<p>clip</p>"#,
+            r#"<video poster="https://nitter.net/pic/amplify_video_thumb%2F400%2Fimg%2Fvid.jpg">"#,
+            r#"<source src="https://nitter.net/video/ABCDEF/https%3A%2F%2Fvideo.twimg.com%2Famplify_video%2F400%2Fvid%2F1280x720%2Fvid.mp4%3Ftag%3D14" type="video/mp4"></video>"#,
+            r#"<video class="gif" poster="https://nitter.net/pic/tweet_video_thumb%2FGif.jpg" autoplay muted loop>"#,
+            r#"<source src="https://nitter.net/pic/video.twimg.com%2Ftweet_video%2FGif.mp4" type="video/mp4"></video>"#,
+        );
+        let nitter_url = Url::parse("https://nitter.net/test/status/100#m").unwrap();
+
+        let content = parse(description, &nitter_url);
+
+        assert_eq!("clip", content.text);
+        assert!(content.pics.is_empty());
+        assert_eq!(
+            vec![
+                Video {
+                    url: Url::parse(
+                        "https://video.twimg.com/amplify_video/400/vid/1280x720/vid.mp4?tag=14"
+                    )
+                    .ok(),
+                    thumb: Url::parse("https://pbs.twimg.com/amplify_video_thumb/400/img/vid.jpg")
+                        .ok(),
+                    is_gif: false,
+                },
+                Video {
+                    url: Url::parse("https://video.twimg.com/tweet_video/Gif.mp4").ok(),
+                    thumb: Url::parse("https://pbs.twimg.com/tweet_video_thumb/Gif.jpg").ok(),
+                    is_gif: true,
+                },
+            ],
+            content.videos
+        );
+    }
+
+    #[test]
+    fn parse_test() {
+        let description = concat!(
+            r#"<p>Hello <a href="https://nitter.net/jack">@jack</a>,<br>"#,
+            r#"see <a href="https://example.com/page?a=1">example.com/page…</a> "#,
+            r##"<a href="https://nitter.net/search?q=%23rust">#rust</a> "##,
+            r#"<a href="https://nitter.net/other/status/200#m">nitter.net/other/status/200#m</a></p>"#,
+            r#"<img src="https://nitter.net/pic/media%2FFabc.jpg" style="max-width:250px;" />"#,
+            r#"<img src="https://nitter.net/pic/ext_tw_video_thumb%2F300%2Fpu%2Fimg%2Fdef.jpg" />"#,
+        );
+        let nitter_url = Url::parse("https://nitter.net/test/status/100#m").unwrap();
+
+        let content = parse(description, &nitter_url);
+
+        assert_eq!(
+            "Hello @jack,\nsee https://example.com/page?a=1 #rust https://twitter.com/other/status/200",
+            content.text
+        );
+        assert_eq!(vec!["jack"], content.entities.mentions);
+        assert_eq!(vec!["rust"], content.entities.hashtags);
+        assert_eq!(
+            vec!["https://example.com/page?a=1"],
+            content
+                .entities
+                .links
+                .iter()
+                .map(Url::as_str)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some("https://twitter.com/other/status/200"),
+            content.quote_url.as_ref().map(Url::as_str)
+        );
+        assert_eq!(
+            vec!["https://pbs.twimg.com/media/Fabc.jpg"],
+            content.pics.iter().map(Url::as_str).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![Video {
+                url: None,
+                thumb: Url::parse("https://pbs.twimg.com/ext_tw_video_thumb/300/pu/img/def.jpg")
+                    .ok(),
+                is_gif: false,
+            }],
+            content.videos
+        );
+    }
+}
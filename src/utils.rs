@@ -2,11 +2,14 @@ use anyhow::Context as _;
 use base64::{engine::general_purpose, Engine as _};
 use chrono::Local;
 use dotenvy::dotenv;
+use percent_encoding::percent_decode_str;
 use regex::Regex;
 use slack_morphism::{SlackApiToken, SlackApiTokenType, SlackApiTokenValue};
 use std::env;
 use url::Url;
 
+const VIDEO_HOST: &str = "video.twimg.com/";
+
 pub fn get_token(token_type: &SlackApiTokenType) -> anyhow::Result<SlackApiToken> {
     dotenv().ok();
     let token_key = match token_type {
@@ -33,6 +36,41 @@ pub fn nitter_url_to_nitter(nitter_url: &Url) -> anyhow::Result<&str> {
     Ok(nitter)
 }
 
+// 購読のキーである RSS URL を、現在の取得元インスタンスの URL に置き換える
+pub fn rss_url_on_nitter(rss_url: &Url, nitter: &str) -> anyhow::Result<Url> {
+    let mut nitter_rss_url = rss_url.clone();
+    nitter_rss_url.set_host(Some(nitter))?;
+    Ok(nitter_rss_url)
+}
+
+// 同じフィードを 1 つのキーで扱えるよう、小文字にして末尾の / とクエリを取り除く
+// expected input: https://nitter.net/TwitterJP/rss/?a=b
+// expected output: https://nitter.net/twitterjp/rss
+pub fn canonical_rss_url(rss_url: &Url) -> Url {
+    let mut canonical = rss_url.clone();
+    canonical.set_path(&rss_url.path().trim_end_matches('/').to_lowercase());
+    canonical.set_query(None);
+    canonical.set_fragment(None);
+    canonical
+}
+
+// expected input: @TwitterJP
+// expected output: twitterjp
+pub fn canonical_account(account: &str) -> String {
+    account.trim_start_matches('@').to_lowercase()
+}
+
+// RSS / Atom フィードは URL を名前にしているため @ を付けない
+// expected input: twitterjp, blog.rust-lang.org/feed.xml
+// expected output: @twitterjp, blog.rust-lang.org/feed.xml
+pub fn display_account(account: &str) -> String {
+    if account.contains('/') {
+        account.to_string()
+    } else {
+        format!("@{account}")
+    }
+}
+
 pub fn url_to_account(nitter_url: &Url) -> anyhow::Result<&str> {
     let account = nitter_url
         .path_segments()
@@ -41,8 +79,16 @@ pub fn url_to_account(nitter_url: &Url) -> anyhow::Result<&str> {
     Ok(account)
 }
 
+// expected output: {status id}
+pub fn status_id(tweet_url: &str) -> Option<String> {
+    let re = Regex::new(r"/status/(\d+)").unwrap();
+    let captures = re.captures(tweet_url)?;
+    Some(captures.get(1)?.as_str().to_string())
+}
+
 pub fn is_retweet(tweet_url: &Url, nitter_account: &str) -> bool {
-    url_to_account(tweet_url).map_or(false, |twi_account| twi_account != nitter_account)
+    url_to_account(tweet_url)
+        .is_ok_and(|twi_account| !twi_account.eq_ignore_ascii_case(nitter_account))
 }
 pub fn account_to_twitter_profile(account: &str) -> anyhow::Result<Url> {
     let twitter_host = Url::parse("https://twitter.com/")?;
@@ -51,9 +97,55 @@ pub fn account_to_twitter_profile(account: &str) -> anyhow::Result<Url> {
     Ok(tweet_url)
 }
 
+pub fn escape_mrkdwn(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// <url|label> の区切りとして解釈されないよう、URL 中の | < > を符号化する
+pub fn escape_mrkdwn_url(url: &Url) -> String {
+    url.as_str()
+        .replace('|', "%7C")
+        .replace('<', "%3C")
+        .replace('>', "%3E")
+}
+
+// エスケープ済みの mrkdwn を、文字参照やリンクの途中で切らずに limit 文字以内に収める
+pub fn truncate_mrkdwn(text: &str, limit: usize) -> &str {
+    let mut end = 0;
+    let mut closing = None;
+    for (count, (i, c)) in text.char_indices().enumerate() {
+        if count >= limit {
+            return &text[..end];
+        }
+        match (closing, c) {
+            (None, '&') => closing = Some(';'),
+            (None, '<') => closing = Some('>'),
+            (Some(close), c) if c == close => closing = None,
+            _ => {}
+        }
+        if closing.is_none() {
+            end = i + c.len_utf8();
+        }
+    }
+    text
+}
+
 pub fn validate_display_name(display_name: &str, account: &str) -> String {
+    // 購読しているアカウント名は小文字にしているため、大文字小文字を区別せずに取り除く
     let end_pattern = format!(" / @{account}");
-    display_name.trim_end_matches(&end_pattern).to_string()
+    let split = display_name.len().saturating_sub(end_pattern.len());
+    match display_name.get(split..) {
+        Some(end) if end.eq_ignore_ascii_case(&end_pattern) => display_name[..split].to_string(),
+        _ => display_name.to_string(),
+    }
+}
+// API の日時 (RFC 3339) を RSS と同じ RFC 2822 の形式にする
+// expected input: 2023-06-01T00:00:00.000Z
+pub fn rfc2822(created_at: &str) -> Option<String> {
+    let date = chrono::DateTime::parse_from_rfc3339(created_at).ok()?;
+    Some(date.to_rfc2822())
 }
 pub fn _print_datetime() {
     let datetime = Local::now();
@@ -62,20 +154,91 @@ pub fn _print_datetime() {
 }
 // expected output: media/{id}.jpg
 pub fn get_image_id(url_src: &Url) -> Option<String> {
-    let re = Regex::new(r#"/([^/]+)$"#).unwrap();
+    let re = Regex::new(r"/([^/]+)$").unwrap();
     let captures = re.captures(url_src.as_str())?;
     let id_raw = captures.get(1)?.as_str();
 
-    if id_raw.starts_with("media") {
+    if id_raw.starts_with("media") || is_video_thumb(id_raw) {
         Some(id_raw.replace("%2F", "/"))
     } else {
         decode_base64(id_raw).ok()
     }
 }
 
+// Nitter が中継している動画の元の URL
+// expected input: https://{nitter}/video/{hmac}/https%3A%2F%2Fvideo.twimg.com%2F..., https://{nitter}/pic/video.twimg.com%2Ftweet_video%2F{id}.mp4
+// expected output: https://video.twimg.com/...
+pub fn get_video_url(url_src: &Url) -> Option<Url> {
+    let path = percent_decode_str(url_src.path()).decode_utf8().ok()?;
+    let decoded = if path.contains(VIDEO_HOST) {
+        path.to_string()
+    } else {
+        // base64 で隠されている場合 (/video/enc/..., /pic/enc/...)
+        decode_base64(path.rsplit('/').next()?).ok()?
+    };
+    let start = decoded.find(VIDEO_HOST)?;
+    Url::parse(&format!("https://{}", &decoded[start..])).ok()
+}
+
+// 動画や GIF のサムネイルの画像 ID
+pub fn is_video_thumb(image_id: &str) -> bool {
+    const PREFIXES: [&str; 3] = [
+        "ext_tw_video_thumb",
+        "tweet_video_thumb",
+        "amplify_video_thumb",
+    ];
+    PREFIXES.iter().any(|prefix| image_id.starts_with(prefix))
+}
+
 fn decode_base64(id_base64: &str) -> anyhow::Result<String> {
     let bytes = general_purpose::STANDARD.decode(id_base64)?;
     let decoded_id = String::from_utf8(bytes)?;
 
     Ok(decoded_id)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_mrkdwn_test() {
+        // expected input: エスケープ済みの文字列
+        // expected output: 文字参照やリンクを含まない位置で切り詰めた文字列
+        let text = escape_mrkdwn("a<b & c");
+        assert_eq!("a&lt;b &amp; c", text);
+        assert_eq!("a&lt;b ", truncate_mrkdwn(&text, 10));
+        assert_eq!("a", truncate_mrkdwn(&text, 3));
+        assert_eq!(text, truncate_mrkdwn(&text, 14));
+
+        assert_eq!("ab ", truncate_mrkdwn("ab <https://a.b|c> d", 10));
+    }
+
+    #[test]
+    fn escape_mrkdwn_url_test() {
+        let url = Url::parse("https://example.com/a|b?q=<c>").unwrap();
+        assert_eq!(
+            "https://example.com/a%7Cb?q=%3Cc%3E",
+            escape_mrkdwn_url(&url)
+        );
+    }
+
+    #[test]
+    fn canonical_test() {
+        let canonical = |url: &str| canonical_rss_url(&Url::parse(url).unwrap()).to_string();
+        let expected = "https://nitter.net/twitterjp/rss";
+
+        assert_eq!(expected, canonical("https://nitter.net/TwitterJP/rss"));
+        assert_eq!(expected, canonical("https://Nitter.NET/twitterjp/rss/"));
+        assert_eq!(
+            expected,
+            canonical("https://nitter.net/twitterjp/rss?max_position=1")
+        );
+        assert_eq!("twitterjp", canonical_account("@TwitterJP"));
+
+        assert_eq!(
+            "Twitter Japan",
+            validate_display_name("Twitter Japan / @TwitterJP", "twitterjp")
+        );
+    }
+}